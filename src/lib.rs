@@ -1,27 +1,51 @@
 #![no_std] // don't link the Rust standard library
+#![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![allow(clippy::missing_safety_doc)]
 
 extern crate alloc;
 
 use bootloader::BootInfo;
+use core::panic::PanicInfo;
 use low_level::{
-    allocator, gdt, interrupts,
+    acpi, allocator, boot_info, clock, gdt, interrupts,
     memory::{self, PopFrameAllocator},
+    serial, stack, syscall, timer, vmm,
 };
 use x86_64::VirtAddr;
 
 pub mod low_level;
+pub mod task;
 pub mod userspace;
 pub fn init(boot_info: &'static BootInfo) {
+    boot_info::init(boot_info);
     initialize_gdt_and_interrupts();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
 
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { PopFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { PopFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
+    vmm::init(phys_mem_offset, &boot_info.memory_map);
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    gdt::init_guarded_stacks();
+    stack::register_boot_stack();
+    acpi::init();
+    //ACPI tells where the RTC keeps the century
+    clock::init();
+    interrupts::init_apic();
+    task::thread::init();
+    boot_info::log_report();
+    log!("{}", memory::stats());
+    if let Some(now) = clock::now() {
+        log!("Booted at {}", now);
+    }
 }
 
 pub fn hlt_loop() -> ! {
@@ -29,9 +53,92 @@ pub fn hlt_loop() -> ! {
         x86_64::instructions::hlt();
     }
 }
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    error!(
+        "allocation of {:?} failed, heap: {} of {} bytes used, limit {}",
+        layout,
+        allocator::heap_used(),
+        allocator::heap_size(),
+        allocator::heap_limit()
+    );
+    panic!("allocation error: {:?}", layout)
+}
+
 fn initialize_gdt_and_interrupts() {
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    timer::init(timer::DEFAULT_TIMER_FREQUENCY);
+    serial::init();
+    interrupts::init_input();
     x86_64::instructions::interrupts::enable();
 }
+
+/// Anything that can be run by the `#[test_case]` runner.
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    //keep the screen output of the tests from cluttering the results
+    userspace::output::set_serial_mirroring(false);
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+/// Exit codes for QEMU's `isa-debug-exit` device. QEMU exits with `(code << 1) | 1`,
+/// so they must not collide with QEMU's own exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Writes the exit code to the `isa-debug-exit` device (configured in `Cargo.toml`),
+/// which makes QEMU exit immediately.
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        let mut port = Port::new(0xf4);
+        port.write(exit_code as u32);
+    }
+}
+
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
+/// Entry point for `cargo test`
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
+    test_main();
+    hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
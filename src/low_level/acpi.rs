@@ -0,0 +1,419 @@
+//ACPI tables, the firmware's description of hardware that can't be probed for.
+//They are read through the physical memory mapping the bootloader set up.
+use alloc::vec::Vec;
+use core::{ptr, slice};
+use spin::Once;
+
+use crate::{
+    log,
+    low_level::{boot_info, rtc},
+    warn,
+};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Real mode segment of the extended BIOS data area is stored here
+const EBDA_POINTER: u64 = 0x40E;
+const EBDA_SEARCH_LENGTH: u64 = 1024;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+const SDT_HEADER_LENGTH: usize = 36;
+const MADT_ENTRIES_START: usize = 44;
+/// ACPI 1.0 FADTs end after the flags
+const FADT_FLAGS_END: usize = 116;
+/// Before ACPI 2.0 the FADT ended after the reset register
+const FADT_RESET_VALUE_END: usize = 129;
+const HPET_LENGTH: usize = 56;
+const FADT_X_DSDT_END: usize = 148;
+const FADT_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+static TABLES: Once<Vec<Table>> = Once::new();
+static MADT: Once<Madt> = Once::new();
+static FADT: Once<Fadt> = Once::new();
+static HPET: Once<Hpet> = Once::new();
+
+/// The header every system description table starts with
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub signature: [u8; 4],
+    /// Physical address of the table
+    pub address: u64,
+    /// Length including the header
+    pub length: u32,
+    pub revision: u8,
+}
+
+impl Table {
+    unsafe fn at(address: u64) -> Self {
+        let header = physical_bytes(address, SDT_HEADER_LENGTH);
+        Table {
+            signature: [header[0], header[1], header[2], header[3]],
+            address,
+            length: read_u32(header, 4),
+            revision: header[8],
+        }
+    }
+    /// The complete table, header included
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { physical_bytes(self.address, self.length as usize) }
+    }
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+    /// All bytes of a table add up to zero
+    pub fn checksum_ok(&self) -> bool {
+        checksum_ok(self.bytes())
+    }
+}
+
+/// How ACPI describes a register: an I/O port or a physical memory address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    Other(u8),
+}
+
+impl GenericAddress {
+    fn parse(bytes: &[u8], offset: usize) -> Self {
+        GenericAddress {
+            address_space: match bytes[offset] {
+                0 => AddressSpace::Memory,
+                1 => AddressSpace::Io,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: bytes[offset + 1],
+            bit_offset: bytes[offset + 2],
+            address: read_u64(bytes, offset + 4),
+        }
+    }
+}
+
+/// Multiple APIC Description Table, lists the interrupt controllers
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// Physical address of every CPU's local APIC
+    pub local_apic_address: u64,
+    /// Whether there are 8259 PICs besides the APICs
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt this I/O APIC handles
+    pub gsi_base: u32,
+}
+
+/// A legacy IRQ that isn't wired to the I/O APIC input of the same number
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    /// The legacy IRQ
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    // 0 means "as the bus does it", for ISA that is active high and edge triggered
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// Fixed ACPI Description Table, where the power management hardware is
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Physical address of the DSDT, the AML code describing the rest of the machine
+    pub dsdt: u64,
+    /// IRQ of the system control interrupt
+    pub sci_interrupt: u16,
+    /// Writing `acpi_enable` here switches the machine from legacy to ACPI mode
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    /// CMOS register of the century, 0 if the RTC has none
+    pub century_register: u8,
+    pub flags: u32,
+    /// Writing `reset_value` to it resets the machine, if `supports_reset_register`
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub fn supports_reset_register(&self) -> bool {
+        self.flags & FADT_RESET_REGISTER_SUPPORTED != 0 && self.reset_register.is_some()
+    }
+}
+
+/// High Precision Event Timer description
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// Smallest period the comparators can be set to in periodic mode, in main counter ticks
+    pub minimum_tick: u16,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub pci_vendor_id: u16,
+}
+
+/// Finds the tables and parses the ones the kernel uses, needs the heap.
+/// Returns false if the firmware doesn't provide ACPI.
+pub fn init() -> bool {
+    let found = unsafe { find_tables() };
+    let Some(found) = found else {
+        warn!("No ACPI tables found");
+        return false;
+    };
+    TABLES.call_once(|| found);
+    for table in tables() {
+        log!("ACPI table {} at {:#x}", table.signature(), table.address);
+    }
+    if let Some(madt) = find_table(b"APIC").and_then(|table| parse_madt(table.bytes())) {
+        let madt = MADT.call_once(|| madt);
+        log!(
+            "{} CPUs, {} I/O APICs, {} interrupt overrides",
+            madt.processors.iter().filter(|cpu| cpu.enabled).count(),
+            madt.io_apics.len(),
+            madt.overrides.len()
+        );
+    }
+    if let Some(fadt) = find_table(b"FACP").and_then(|table| parse_fadt(table.bytes())) {
+        let fadt = FADT.call_once(|| fadt);
+        rtc::set_century_register(fadt.century_register);
+    }
+    if let Some(hpet) = find_table(b"HPET").and_then(|table| parse_hpet(table.bytes())) {
+        HPET.call_once(|| hpet);
+    }
+    true
+}
+
+/// Every table the RSDT/XSDT points to, empty before `init`
+pub fn tables() -> &'static [Table] {
+    TABLES.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    tables()
+        .iter()
+        .find(|table| &table.signature == signature)
+        .copied()
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    MADT.get()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    FADT.get()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// The DSDT isn't listed in the RSDT, the FADT points to it
+pub fn dsdt() -> Option<Table> {
+    let fadt = fadt()?;
+    let dsdt = unsafe { Table::at(fadt.dsdt) };
+    (&dsdt.signature == b"DSDT" && dsdt.checksum_ok()).then_some(dsdt)
+}
+
+unsafe fn physical_bytes(address: u64, length: usize) -> &'static [u8] {
+    let virtual_address = boot_info::physical_memory_offset() + address;
+    slice::from_raw_parts(virtual_address.as_ptr(), length)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 4].as_ptr() as *const u32) }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 8].as_ptr() as *const u64) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// The RSDP is on a 16 byte boundary in the first KiB of the EBDA or in the BIOS area.
+/// Returns its physical address.
+unsafe fn find_rsdp() -> Option<u64> {
+    let ebda = (read_u16(physical_bytes(EBDA_POINTER, 2), 0) as u64) << 4;
+    [
+        (ebda, ebda + EBDA_SEARCH_LENGTH),
+        (BIOS_AREA_START, BIOS_AREA_END),
+    ]
+    .into_iter()
+    .filter(|&(start, _)| start != 0)
+    .flat_map(|(start, end)| (start..end).step_by(16))
+    .find(|&address| {
+        let rsdp = physical_bytes(address, RSDP_V1_LENGTH);
+        &rsdp[..8] == RSDP_SIGNATURE && checksum_ok(rsdp)
+    })
+}
+
+unsafe fn find_tables() -> Option<Vec<Table>> {
+    let rsdp_address = find_rsdp()?;
+    let rsdp = physical_bytes(rsdp_address, RSDP_V1_LENGTH);
+    //ACPI 2.0 and later have the XSDT with 64-bit pointers
+    let extended = physical_bytes(rsdp_address, RSDP_V2_LENGTH);
+    let (root, entry_size) = if rsdp[15] >= 2 && checksum_ok(extended) {
+        (read_u64(extended, 24), 8)
+    } else {
+        (read_u32(rsdp, 16) as u64, 4)
+    };
+    let root = Table::at(root);
+    if !root.checksum_ok() {
+        warn!("ACPI {} has a bad checksum", root.signature());
+        return None;
+    }
+    let entries = &root.bytes()[SDT_HEADER_LENGTH..];
+    let tables = entries
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0) as u64,
+        })
+        .map(|address| Table::at(address))
+        .filter(|table| {
+            let ok = table.checksum_ok();
+            if !ok {
+                warn!(
+                    "Ignoring ACPI table {} with a bad checksum",
+                    table.signature()
+                );
+            }
+            ok
+        })
+        .collect();
+    Some(tables)
+}
+
+/// None if the table is too short to be one, the same for the other parsers
+fn parse_madt(bytes: &'static [u8]) -> Option<Madt> {
+    if bytes.len() < MADT_ENTRIES_START {
+        return None;
+    }
+    let mut madt = Madt {
+        local_apic_address: read_u32(bytes, 36) as u64,
+        has_legacy_pics: read_u32(bytes, 40) & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut offset = MADT_ENTRIES_START;
+    while offset + 2 <= bytes.len() {
+        let (entry_type, length) = (bytes[offset], bytes[offset + 1] as usize);
+        if length < 2 || offset + length > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + length];
+        match entry_type {
+            //entries too short for their type are skipped
+            0 if length >= 8 => madt.processors.push(Processor {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            1 if length >= 12 => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            2 if length >= 10 => madt.overrides.push(InterruptOverride {
+                bus: entry[2],
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            }),
+            //64-bit local APIC address
+            5 if length >= 12 => madt.local_apic_address = read_u64(entry, 4),
+            _ => {}
+        }
+        offset += length;
+    }
+    Some(madt)
+}
+
+fn parse_fadt(bytes: &'static [u8]) -> Option<Fadt> {
+    if bytes.len() < FADT_FLAGS_END {
+        return None;
+    }
+    let has_reset_register = bytes.len() >= FADT_RESET_VALUE_END;
+    //the 64-bit DSDT address wins if it is there
+    let x_dsdt = if bytes.len() >= FADT_X_DSDT_END {
+        read_u64(bytes, 140)
+    } else {
+        0
+    };
+    Some(Fadt {
+        dsdt: if x_dsdt != 0 {
+            x_dsdt
+        } else {
+            read_u32(bytes, 40) as u64
+        },
+        sci_interrupt: read_u16(bytes, 46),
+        smi_command_port: read_u32(bytes, 48),
+        acpi_enable: bytes[52],
+        acpi_disable: bytes[53],
+        pm1a_event_block: read_u32(bytes, 56),
+        pm1b_event_block: read_u32(bytes, 60),
+        pm1a_control_block: read_u32(bytes, 64),
+        pm1b_control_block: read_u32(bytes, 68),
+        pm_timer_block: read_u32(bytes, 76),
+        century_register: bytes[108],
+        flags: read_u32(bytes, 112),
+        reset_register: has_reset_register.then(|| GenericAddress::parse(bytes, 116)),
+        reset_value: if has_reset_register { bytes[128] } else { 0 },
+    })
+}
+
+fn parse_hpet(bytes: &'static [u8]) -> Option<Hpet> {
+    if bytes.len() < HPET_LENGTH {
+        return None;
+    }
+    let block_id = read_u32(bytes, 36);
+    Some(Hpet {
+        base_address: GenericAddress::parse(bytes, 40),
+        hpet_number: bytes[52],
+        minimum_tick: read_u16(bytes, 53),
+        comparators: ((block_id >> 8) & 0x1F) as u8 + 1,
+        counter_64bit: block_id & (1 << 13) != 0,
+        pci_vendor_id: (block_id >> 16) as u16,
+    })
+}
@@ -1,44 +1,275 @@
-use linked_list_allocator::LockedHeap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use linked_list_allocator::Heap;
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, Mapper, Page, PageTableFlags,
-        Size4KiB,
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper,
+        Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+use crate::low_level::{
+    memory,
+    vmm::{self, Backing, VmmError},
+};
+
+#[cfg(feature = "slab-allocator")]
+mod slab;
+
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB, mapped at boot
+/// The heap never grows past this, the virtual range up to it is reserved for the heap
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// The heap grows by at least this much at once, so it doesn't map pages one by one
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+const PAGE_SIZE: usize = 4096;
 
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+//Build with `--no-default-features` to use the linked list heap for everything
+#[cfg(feature = "slab-allocator")]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator<slab::SlabAllocator> =
+    CountingAllocator::new(slab::SlabAllocator::new(GrowableHeap::empty()));
+#[cfg(not(feature = "slab-allocator"))]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: CountingAllocator<GrowableHeap> = CountingAllocator::new(GrowableHeap::empty());
+
+/// The linked list heap, behind the slab allocator if that is enabled
+fn heap() -> &'static GrowableHeap {
+    #[cfg(feature = "slab-allocator")]
+    return ALLOCATOR.inner.fallback();
+    #[cfg(not(feature = "slab-allocator"))]
+    return &ALLOCATOR.inner;
+}
+
+/// Wraps the global allocator and keeps track of what goes through it
+pub struct CountingAllocator<A> {
+    inner: A,
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+}
+
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        CountingAllocator {
+            inner,
+            bytes_in_use: AtomicUsize::new(0),
+            peak_bytes_in_use: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocation = self.inner.alloc(layout);
+        if !allocation.is_null() {
+            let in_use = self
+                .bytes_in_use
+                .fetch_add(layout.size(), Ordering::Relaxed);
+            self.peak_bytes_in_use
+                .fetch_max(in_use + layout.size(), Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        allocation
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.bytes_in_use
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Size of the heap, including the space that got added by growing
+    pub size: usize,
+    /// Bytes of live allocations, as requested by their layouts
+    pub used: usize,
+    pub peak_used: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "heap {} of {} KiB used (peak {} KiB), {} allocs, {} frees",
+            self.used / 1024,
+            self.size / 1024,
+            self.peak_used / 1024,
+            self.allocations,
+            self.deallocations
+        )
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        size: heap_size(),
+        used: ALLOCATOR.bytes_in_use.load(Ordering::Relaxed),
+        peak_used: ALLOCATOR.peak_bytes_in_use.load(Ordering::Relaxed),
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed),
+        deallocations: ALLOCATOR.deallocations.load(Ordering::Relaxed),
+    }
+}
+
+/// Linked list heap that maps more pages through the installed page table and
+/// frame allocator (see `memory::install`) when it runs out of space.
+pub struct GrowableHeap {
+    heap: Mutex<Heap>,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> Self {
+        GrowableHeap {
+            heap: Mutex::new(Heap::empty()),
+        }
+    }
+
+    /// Maps enough pages after the end of the heap to fit `layout` and adds them to it
+    fn grow(heap: &mut Heap, layout: Layout) -> Result<(), MapToError<Size4KiB>> {
+        //worst case the allocation has to be aligned past the start of the new space
+        let needed = layout.size() + layout.align();
+        let room = heap_limit().saturating_sub(heap.size()) / PAGE_SIZE * PAGE_SIZE;
+        //close to the limit a smaller step does, as long as the allocation still fits
+        let by = (needed.max(HEAP_GROWTH_STEP).div_ceil(PAGE_SIZE) * PAGE_SIZE).min(room);
+        if by < needed {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
+        let start = VirtAddr::from_ptr(heap.top());
+        let page_range = Page::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(start + by - 1u64),
+        );
+        memory::with_mapper_and_allocator(|mapper, frame_allocator| {
+            map_pages(page_range, mapper, frame_allocator)
+        })
+        .unwrap_or(Err(MapToError::FrameAllocationFailed))?;
+
+        unsafe { heap.extend(by) };
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        loop {
+            if let Ok(allocation) = heap.allocate_first_fit(layout) {
+                return allocation.as_ptr();
+            }
+            if Self::grow(&mut heap, layout).is_err() {
+                return ptr::null_mut();
+            }
+        }
+    }
 
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+/// Heap size in bytes, including the space that got added by growing
+pub fn heap_size() -> usize {
+    heap().heap.lock().size()
+}
+
+/// Bytes currently handed out by the linked list heap, free blocks of the slab allocator count as used
+pub fn heap_used() -> usize {
+    heap().heap.lock().used()
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Changes how far the heap may grow, capped at HEAP_MAX_SIZE. Space it already has is kept.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+pub enum HeapError {
+    /// No address space could be reserved for the heap
+    Vmm(VmmError),
+    Map(MapToError<Size4KiB>),
+}
+
+/// Reserves the address space for the heap and maps its first HEAP_SIZE bytes
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), HeapError> {
+    let area = vmm::allocate(
+        "kernel heap",
+        HEAP_MAX_SIZE as u64,
+        PageTableFlags::WRITABLE,
+        Backing::Anonymous { on_demand: false },
+    )
+    .map_err(HeapError::Vmm)?;
+    map_pages(get_page_range(area.start), mapper, frame_allocator).map_err(HeapError::Map)?;
+    create_empty_heap(area.start);
+    Ok(())
+}
+/// Maps fresh frames at `page_range`. On failure the pages mapped so far are unmapped and
+/// their frames freed again, so a later attempt can start from the same page.
+fn map_pages(
+    page_range: PageRangeInclusive,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = get_page_range();
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for (mapped, page) in page_range.enumerate() {
+        let result = match frame_allocator.allocate_frame() {
+            Some(frame) => match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(())
+                }
+                Err(error) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(error)
+                }
+            },
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        if let Err(error) = result {
+            for page in page_range.take(mapped) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            return Err(error);
+        }
     }
-    create_empty_heap();
     Ok(())
 }
-fn get_page_range() -> PageRangeInclusive {
-    let heap_start = VirtAddr::new(HEAP_START as u64);
+fn get_page_range(heap_start: VirtAddr) -> PageRangeInclusive {
     let heap_end = heap_start + HEAP_SIZE - 1u64;
     let heap_start_page = Page::containing_address(heap_start);
     let heap_end_page = Page::containing_address(heap_end);
     Page::range_inclusive(heap_start_page, heap_end_page)
 }
-fn create_empty_heap() {
+fn create_empty_heap(heap_start: VirtAddr) {
     unsafe {
-        let raw_heap_start = HEAP_START as *mut u8;
-        ALLOCATOR.lock().init(raw_heap_start, HEAP_SIZE);
+        let raw_heap_start = heap_start.as_mut_ptr::<u8>();
+        heap().heap.lock().init(raw_heap_start, HEAP_SIZE);
     }
 }
@@ -0,0 +1,97 @@
+//Fixed-size-block allocator: small allocations are rounded up to a power of two and served
+//from per-size free lists. Blocks are cut from slabs taken from the linked list heap and are
+//never given back to it, freed blocks go onto the free list of their size instead.
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+use spin::Mutex;
+
+use super::GrowableHeap;
+
+/// Sizes of the blocks, also their alignment. Anything larger goes straight to the heap.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Size of the chunk taken from the heap whenever a free list runs dry
+const SLAB_SIZE: usize = 4096;
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+//every block can hold a node, the smallest block size is the size of one
+const _: () = assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[0]);
+const _: () = assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[0]);
+
+pub struct SlabAllocator {
+    list_heads: Mutex<[Option<&'static mut ListNode>; BLOCK_SIZES.len()]>,
+    fallback: GrowableHeap,
+}
+
+impl SlabAllocator {
+    pub const fn new(fallback: GrowableHeap) -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        SlabAllocator {
+            list_heads: Mutex::new([EMPTY; BLOCK_SIZES.len()]),
+            fallback,
+        }
+    }
+
+    /// The heap that slabs and large allocations come from
+    pub fn fallback(&self) -> &GrowableHeap {
+        &self.fallback
+    }
+
+    /// Index of the smallest block size that fits the layout, None if it is too large
+    fn size_class(layout: &Layout) -> Option<usize> {
+        let required_block_size = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+    }
+
+    /// Cuts a new slab into blocks of the given class and pushes them onto its list
+    unsafe fn refill(&self, list_head: &mut Option<&'static mut ListNode>, block_size: usize) {
+        let slab_layout = Layout::from_size_align_unchecked(SLAB_SIZE, block_size);
+        let slab = self.fallback.alloc(slab_layout);
+        if slab.is_null() {
+            return;
+        }
+        for offset in (0..SLAB_SIZE).step_by(block_size).rev() {
+            let node_ptr = slab.add(offset) as *mut ListNode;
+            node_ptr.write(ListNode {
+                next: list_head.take(),
+            });
+            *list_head = Some(&mut *node_ptr);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(index) = Self::size_class(&layout) else {
+            return self.fallback.alloc(layout);
+        };
+        let mut list_heads = self.list_heads.lock();
+        if list_heads[index].is_none() {
+            self.refill(&mut list_heads[index], BLOCK_SIZES[index]);
+        }
+        match list_heads[index].take() {
+            Some(node) => {
+                list_heads[index] = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(index) = Self::size_class(&layout) else {
+            return self.fallback.dealloc(ptr, layout);
+        };
+        let mut list_heads = self.list_heads.lock();
+        let node_ptr = NonNull::new_unchecked(ptr as *mut ListNode).as_ptr();
+        node_ptr.write(ListNode {
+            next: list_heads[index].take(),
+        });
+        list_heads[index] = Some(&mut *node_ptr);
+    }
+}
@@ -0,0 +1,259 @@
+//Local APIC and I/O APIC, used instead of the 8259 PICs when the machine has them.
+//The local APIC takes over the timer, the I/O APIC delivers the legacy IRQs (keyboard, serial, ...).
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+use crate::low_level::{
+    acpi::{self, InterruptOverride},
+    interrupts::TIMER_IRQ,
+    irq, timer, vmm,
+};
+
+const MAX_IO_APICS: usize = 8;
+const PAGE_SIZE: u64 = 4096;
+
+const APIC_BASE_MSR: u32 = 0x1B;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+/// CPUID leaf 1, EDX
+const CPUID_APIC: u32 = 1 << 9;
+
+// Local APIC register offsets
+const LOCAL_APIC_ID: u64 = 0x20;
+const TASK_PRIORITY: u64 = 0x80;
+const END_OF_INTERRUPT: u64 = 0xB0;
+const SPURIOUS_INTERRUPT: u64 = 0xF0;
+/// Eight 32 bit registers, 16 bytes apart, with a bit per vector
+const IN_SERVICE: u64 = 0x100;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3E0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+/// How long the APIC timer is measured against the PIT
+const CALIBRATION_US: u64 = 10_000;
+
+/// Gets raised instead of an interrupt that went away before it was delivered, needs no EOI
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// I/O APIC registers, selected through IOREGSEL and accessed through IOWIN
+const IO_REGISTER_SELECT: u64 = 0x00;
+const IO_WINDOW: u64 = 0x10;
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Virtual address of the local APIC registers
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+struct IoApic {
+    registers: VirtAddr,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            write_register(self.registers, IO_REGISTER_SELECT, register);
+            read_register(self.registers, IO_WINDOW)
+        }
+    }
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            write_register(self.registers, IO_REGISTER_SELECT, register);
+            write_register(self.registers, IO_WINDOW, value);
+        }
+    }
+    fn set_redirection(&self, input: u32, low: u32, destination: u8) {
+        let register = IO_REDIRECTION_TABLE + input * 2;
+        self.write(register + 1, (destination as u32) << 24);
+        self.write(register, low);
+    }
+}
+
+unsafe fn read_register(base: VirtAddr, offset: u64) -> u32 {
+    ptr::read_volatile((base + offset).as_ptr())
+}
+
+unsafe fn write_register(base: VirtAddr, offset: u64, value: u32) {
+    ptr::write_volatile((base + offset).as_mut_ptr(), value)
+}
+
+fn local_apic() -> VirtAddr {
+    VirtAddr::new(LOCAL_APIC.load(Ordering::Relaxed))
+}
+
+/// Whether interrupts are delivered through the APICs rather than the PICs
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether the CPU has a local APIC
+//__cpuid is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1).edx & CPUID_APIC != 0 }
+}
+
+/// Maps one page of APIC registers, uncached. Fails rather than reusing a mapping of another frame.
+fn map_registers(physical: u64) -> Option<VirtAddr> {
+    vmm::map_mmio(PhysAddr::new(physical), PAGE_SIZE)
+}
+
+/// Disables the PICs and enables the local APIC, its timer and the I/O APICs.
+/// Needs the heap, ACPI and the PIT timer. Returns false and leaves the PICs
+/// in charge if there is no APIC or the MADT doesn't describe it.
+pub fn init() -> bool {
+    if !is_supported() {
+        return false;
+    }
+    let Some(madt) = acpi::madt() else {
+        return false;
+    };
+    if madt.io_apics.is_empty() {
+        return false;
+    }
+    let Some(local_apic) = map_registers(madt.local_apic_address) else {
+        return false;
+    };
+    let mut io_apics = Vec::new();
+    for io_apic in madt.io_apics.iter().take(MAX_IO_APICS) {
+        let Some(registers) = map_registers(io_apic.address as u64) else {
+            return false;
+        };
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base: io_apic.gsi_base,
+            inputs: 0,
+        };
+        io_apic.inputs = ((io_apic.read(IO_APIC_VERSION) >> 16) & 0xFF) + 1;
+        for input in 0..io_apic.inputs {
+            io_apic.set_redirection(input, REDIRECTION_MASKED, 0);
+        }
+        io_apics.push(io_apic);
+    }
+
+    interrupts::without_interrupts(|| {
+        unsafe { crate::low_level::interrupts::PICS.lock().disable() };
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::Relaxed);
+        *IO_APICS.lock() = io_apics;
+        unsafe {
+            let mut base = Msr::new(APIC_BASE_MSR);
+            base.write(base.read() | APIC_GLOBAL_ENABLE);
+            write_register(local_apic, TASK_PRIORITY, 0);
+            write_register(
+                local_apic,
+                SPURIOUS_INTERRUPT,
+                SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+            );
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+    //with the APIC enabled the timer sets up the local APIC timer instead of the PIT
+    timer::init(timer::frequency());
+    true
+}
+
+/// Measures the local APIC timer against the PIT and makes it fire `frequency`
+/// times a second on the timer vector. Returns the length of the tick it could set up in
+/// nanoseconds. Interrupts have to be disabled.
+pub fn start_timer(frequency: u32) -> u64 {
+    let local_apic = local_apic();
+    unsafe {
+        write_register(local_apic, TIMER_DIVIDE, DIVIDE_BY_16);
+        write_register(local_apic, LVT_TIMER, LVT_MASKED);
+        write_register(local_apic, TIMER_INITIAL_COUNT, u32::MAX);
+        timer::busy_wait_us(CALIBRATION_US);
+        let elapsed = u32::MAX - read_register(local_apic, TIMER_CURRENT_COUNT);
+        let per_second = (elapsed as u64 * (1_000_000 / CALIBRATION_US)).max(1);
+        let initial_count = (per_second / frequency.max(1) as u64).clamp(1, u32::MAX as u64);
+
+        let vector = irq::vector_of(TIMER_IRQ) as u32;
+        write_register(local_apic, LVT_TIMER, vector | TIMER_PERIODIC);
+        write_register(local_apic, TIMER_INITIAL_COUNT, initial_count as u32);
+        initial_count * 1_000_000_000 / per_second
+    }
+}
+
+/// APIC ID of the CPU running this
+pub fn local_apic_id() -> u8 {
+    unsafe { (read_register(local_apic(), LOCAL_APIC_ID) >> 24) as u8 }
+}
+
+/// Whether the local APIC delivered `vector` and is waiting for its EOI
+pub fn is_in_service(vector: u8) -> bool {
+    let offset = IN_SERVICE + (vector as u64 / 32) * 0x10;
+    unsafe { read_register(local_apic(), offset) & 1 << (vector % 32) != 0 }
+}
+
+/// Tells the local APIC the current interrupt has been handled
+pub fn end_of_interrupt() {
+    unsafe { write_register(local_apic(), END_OF_INTERRUPT, 0) };
+}
+
+/// Delivers the legacy `irq` on `vector` to this CPU, following the MADT's
+/// interrupt source overrides. Returns false if no I/O APIC handles it.
+pub fn route_irq(irq: u8, vector: u8) -> bool {
+    let (gsi, active_low, level_triggered) = interrupt_override(irq)
+        .map_or((irq as u32, false, false), |entry| {
+            (entry.gsi, entry.active_low(), entry.level_triggered())
+        });
+
+    let mut low = vector as u32;
+    if active_low {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level_triggered {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    let destination = local_apic_id();
+    with_io_apic_input(gsi, |io_apic, input| {
+        io_apic.set_redirection(input, low, destination)
+    })
+}
+
+/// Stops the I/O APIC from delivering the legacy `irq`
+pub fn mask_irq(irq: u8) -> bool {
+    let gsi = interrupt_override(irq).map_or(irq as u32, |entry| entry.gsi);
+    with_io_apic_input(gsi, |io_apic, input| {
+        io_apic.set_redirection(input, REDIRECTION_MASKED, 0)
+    })
+}
+
+/// How the MADT says `irq` is wired, None if it is wired to the I/O APIC input of the same number
+fn interrupt_override(irq: u8) -> Option<&'static InterruptOverride> {
+    acpi::madt()?
+        .overrides
+        .iter()
+        .find(|entry| entry.bus == 0 && entry.source == irq)
+}
+
+/// Runs `f` with the I/O APIC handling `gsi` and its input number, false if there is none
+fn with_io_apic_input(gsi: u32, f: impl FnOnce(&IoApic, u32)) -> bool {
+    interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let Some(io_apic) = io_apics
+            .iter()
+            .find(|io_apic| (io_apic.gsi_base..io_apic.gsi_base + io_apic.inputs).contains(&gsi))
+        else {
+            return false;
+        };
+        f(io_apic, gsi - io_apic.gsi_base);
+        true
+    })
+}
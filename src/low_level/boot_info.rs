@@ -0,0 +1,138 @@
+//What the bootloader told us about the machine, kept around so other subsystems can ask for it
+use bootloader::{
+    bootinfo::{MemoryRegion, MemoryRegionType},
+    BootInfo,
+};
+use core::fmt;
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::log;
+
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+
+/// Keeps the boot info, called first thing in `init`
+pub fn init(boot_info: &'static BootInfo) {
+    BOOT_INFO.call_once(|| boot_info);
+}
+
+/// None until `init` has been called
+pub fn get() -> Option<&'static BootInfo> {
+    BOOT_INFO.get().copied()
+}
+
+fn boot_info() -> &'static BootInfo {
+    get().expect("boot info was not initialized")
+}
+
+/// One entry of the bootloader memory map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryArea {
+    pub start: PhysAddr,
+    /// Exclusive
+    pub end: PhysAddr,
+    pub region_type: MemoryRegionType,
+}
+
+impl MemoryArea {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+    /// Whether the area is RAM, rather than e.g. firmware or an MMIO hole
+    pub fn is_ram(&self) -> bool {
+        !matches!(
+            self.region_type,
+            MemoryRegionType::Reserved | MemoryRegionType::BadMemory
+        )
+    }
+}
+
+impl From<&MemoryRegion> for MemoryArea {
+    fn from(region: &MemoryRegion) -> Self {
+        MemoryArea {
+            start: PhysAddr::new(region.range.start_addr()),
+            end: PhysAddr::new(region.range.end_addr()),
+            region_type: region.region_type,
+        }
+    }
+}
+
+impl fmt::Display for MemoryArea {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#010x}-{:#010x} {:>7} KiB {:?}",
+            self.start.as_u64(),
+            self.end.as_u64(),
+            self.size() / 1024,
+            self.region_type
+        )
+    }
+}
+
+pub fn memory_areas() -> impl Iterator<Item = MemoryArea> {
+    boot_info().memory_map.iter().map(MemoryArea::from)
+}
+
+/// Bytes of RAM in the memory map, whether it is in use or not
+pub fn total_memory() -> u64 {
+    memory_areas()
+        .filter(MemoryArea::is_ram)
+        .map(|area| area.size())
+        .sum()
+}
+
+/// Bytes the frame allocator may hand out
+pub fn usable_memory() -> u64 {
+    memory_areas()
+        .filter(|area| area.region_type == MemoryRegionType::Usable)
+        .map(|area| area.size())
+        .sum()
+}
+
+/// Where the bootloader mapped the complete physical memory
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(boot_info().physical_memory_offset)
+}
+
+/// Physical range the kernel image was loaded to
+pub fn kernel_location() -> Option<(PhysAddr, PhysAddr)> {
+    span_of(MemoryRegionType::Kernel)
+}
+
+/// Physical range of the stack the bootloader set up for `kernel_main`
+pub fn boot_stack_location() -> Option<(PhysAddr, PhysAddr)> {
+    span_of(MemoryRegionType::KernelStack)
+}
+
+fn span_of(region_type: MemoryRegionType) -> Option<(PhysAddr, PhysAddr)> {
+    memory_areas()
+        .filter(|area| area.region_type == region_type)
+        .fold(None, |span, area| match span {
+            None => Some((area.start, area.end)),
+            Some((start, end)) => Some((start.min(area.start), end.max(area.end))),
+        })
+}
+
+/// Logs the memory map and everything else the bootloader passed on
+pub fn log_report() {
+    log!("Memory map:");
+    for area in memory_areas() {
+        log!("{}", area);
+    }
+    log!(
+        "{} MiB RAM, {} MiB usable",
+        total_memory() / (1024 * 1024),
+        usable_memory() / (1024 * 1024)
+    );
+    log!(
+        "Physical memory mapped at {:#x}",
+        physical_memory_offset().as_u64()
+    );
+    if let Some((start, end)) = kernel_location() {
+        log!("Kernel at {:#x}-{:#x}", start.as_u64(), end.as_u64());
+    }
+    if let Some((start, end)) = boot_stack_location() {
+        log!("Boot stack at {:#x}-{:#x}", start.as_u64(), end.as_u64());
+    }
+}
@@ -0,0 +1,36 @@
+//Wall-clock time: the RTC is read once at boot and the timer counts on from there,
+//which is cheaper than reading the CMOS every time and has millisecond resolution.
+use spin::Once;
+
+use crate::low_level::{
+    rtc::{self, DateTime},
+    timer,
+};
+
+/// Unix time in milliseconds and the uptime at which it was read
+static BOOT_TIME: Once<(u64, u64)> = Once::new();
+
+/// Reads the RTC, needs the timer to be running
+pub fn init() {
+    BOOT_TIME.call_once(|| (rtc::read().to_unix() * 1000, timer::uptime_ms()));
+}
+
+/// Milliseconds since 1970 at the given uptime, None before `init`
+pub fn unix_time_ms_at(uptime_ms: u64) -> Option<u64> {
+    let &(boot_unix_ms, boot_uptime_ms) = BOOT_TIME.get()?;
+    Some(boot_unix_ms + uptime_ms.saturating_sub(boot_uptime_ms))
+}
+
+pub fn unix_time_ms() -> Option<u64> {
+    unix_time_ms_at(timer::uptime_ms())
+}
+
+/// Seconds since 1970
+pub fn unix_time() -> Option<u64> {
+    unix_time_ms().map(|ms| ms / 1000)
+}
+
+/// The current date and time, None before `init`
+pub fn now() -> Option<DateTime> {
+    unix_time().map(DateTime::from_unix)
+}
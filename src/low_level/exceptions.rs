@@ -0,0 +1,282 @@
+//Handlers for the CPU exceptions. Everything but the debug traps and page faults
+//in lazily backed memory (see `vmm`) is fatal. In ring 3 that only ends the user task,
+//in the kernel the faulting registers are handed to the panic screen and the kernel panics with a
+//description of the exception and its decoded error code.
+use core::fmt;
+use x86_64::{
+    registers::{control::Cr2, rflags::RFlags},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    low_level::{gdt, stack, vmm},
+    println,
+    task::user,
+    userspace::panic_screen,
+};
+
+/// Installs a handler for every architectural exception
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+    }
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    unsafe {
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception
+        .set_handler_fn(hypervisor_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+/// What the error code pushed by an exception means
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorDetail {
+    None,
+    /// Always zero, e.g. for #DF and #AC
+    Zero(u64),
+    /// #TS, #NP, #SS and #GP: the segment selector or IDT entry that caused the fault
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultErrorCode, VirtAddr),
+    /// A page fault in the guard area below a kernel stack
+    StackOverflow(&'static str, PageFaultErrorCode),
+    Other(u64),
+}
+
+impl ErrorDetail {
+    fn code(&self) -> Option<u64> {
+        match *self {
+            ErrorDetail::None => None,
+            ErrorDetail::Zero(code) | ErrorDetail::Other(code) => Some(code),
+            ErrorDetail::Selector(selector) => Some(selector.0),
+            ErrorDetail::PageFault(code, _) | ErrorDetail::StackOverflow(_, code) => {
+                Some(code.bits())
+            }
+        }
+    }
+}
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorDetail::None | ErrorDetail::Zero(_) => Ok(()),
+            ErrorDetail::Selector(selector) => write!(f, " ({})", selector),
+            ErrorDetail::PageFault(code, address) => {
+                write!(f, " accessing {:#x} ({:?})", address.as_u64(), code)
+            }
+            ErrorDetail::StackOverflow(stack, _) => write!(f, " in {}", stack),
+            ErrorDetail::Other(code) => write!(f, " (error code {:#x})", code),
+        }
+    }
+}
+
+/// Error code of the exceptions caused by segment selectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl SelectorErrorCode {
+    /// The fault happened while delivering an external interrupt
+    pub fn external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+    pub fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1FFF
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "no selector");
+        }
+        write!(f, "{:?} entry {}", self.table(), self.index())?;
+        if self.external() {
+            write!(f, ", external")?;
+        }
+        Ok(())
+    }
+}
+
+/// The shared end of every fatal exception
+fn fault(name: &'static str, stack_frame: &InterruptStackFrame, detail: ErrorDetail) -> ! {
+    //the privilege level the exception came from is in the low bits of the code selector
+    if stack_frame.code_segment & 3 == 3 {
+        user::kill_current(
+            name,
+            format_args!(
+                "{}{} at {:#x}",
+                name,
+                detail,
+                stack_frame.instruction_pointer.as_u64()
+            ),
+        );
+    }
+    panic_screen::report_fault(
+        panic_screen::RegisterDump::from_stack_frame(stack_frame),
+        detail.code(),
+    );
+    panic!(
+        "EXCEPTION: {}{} at {:#x}",
+        name,
+        detail,
+        stack_frame.instruction_pointer.as_u64()
+    );
+}
+
+macro_rules! fault_handler {
+    ($handler:ident, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            fault($name, &stack_frame, ErrorDetail::None);
+        }
+    };
+    ($handler:ident, $name:expr, $detail:path) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            fault($name, &stack_frame, $detail(error_code));
+        }
+    };
+}
+
+fn selector(error_code: u64) -> ErrorDetail {
+    ErrorDetail::Selector(SelectorErrorCode(error_code))
+}
+
+fault_handler!(divide_error_handler, "DIVIDE ERROR");
+fault_handler!(non_maskable_interrupt_handler, "NON-MASKABLE INTERRUPT");
+fault_handler!(overflow_handler, "OVERFLOW");
+fault_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED");
+fault_handler!(invalid_opcode_handler, "INVALID OPCODE");
+fault_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
+fault_handler!(invalid_tss_handler, "INVALID TSS", selector);
+fault_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT", selector);
+fault_handler!(stack_segment_fault_handler, "STACK SEGMENT FAULT", selector);
+fault_handler!(
+    general_protection_fault_handler,
+    "GENERAL PROTECTION FAULT",
+    selector
+);
+fault_handler!(x87_floating_point_handler, "X87 FLOATING POINT");
+fault_handler!(
+    alignment_check_handler,
+    "ALIGNMENT CHECK",
+    ErrorDetail::Zero
+);
+fault_handler!(simd_floating_point_handler, "SIMD FLOATING POINT");
+fault_handler!(virtualization_handler, "VIRTUALIZATION");
+fault_handler!(
+    control_protection_handler,
+    "CONTROL PROTECTION",
+    ErrorDetail::Other
+);
+fault_handler!(hypervisor_injection_handler, "HYPERVISOR INJECTION");
+fault_handler!(
+    vmm_communication_handler,
+    "VMM COMMUNICATION",
+    ErrorDetail::Other
+);
+fault_handler!(
+    security_exception_handler,
+    "SECURITY EXCEPTION",
+    ErrorDetail::Other
+);
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    //a page fault in a stack guard area usually can't push its frame and ends up here
+    if let Some(stack) = stack::overflowed(Cr2::read()) {
+        fault(
+            "KERNEL STACK OVERFLOW",
+            &stack_frame,
+            ErrorDetail::StackOverflow(stack.name, PageFaultErrorCode::empty()),
+        );
+    }
+    fault("DOUBLE FAULT", &stack_frame, ErrorDetail::Zero(error_code));
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    fault("MACHINE CHECK", &stack_frame, ErrorDetail::None);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let address = Cr2::read();
+    if let Some(stack) = stack::overflowed(address) {
+        fault(
+            "KERNEL STACK OVERFLOW",
+            &stack_frame,
+            ErrorDetail::StackOverflow(stack.name, error_code),
+        );
+    }
+    //the first access to a lazily backed page, it gets mapped and the access is retried
+    let interrupts_were_enabled = stack_frame.cpu_flags & RFlags::INTERRUPT_FLAG.bits() != 0;
+    if vmm::handle_page_fault(address, error_code, interrupts_were_enabled) {
+        return;
+    }
+    fault(
+        "PAGE FAULT",
+        &stack_frame,
+        ErrorDetail::PageFault(error_code, address),
+    );
+}
@@ -1,36 +1,56 @@
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicU64, Ordering},
+};
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::low_level::stack;
+
+//Page faults stay on the stack they happen on: they can be resolved and nest,
+//a fresh IST stack would overwrite the frame of the outer one.
+//Those that can't be delivered because the stack overflowed end up as double faults.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const IST_STACK_SIZE: usize = 4096 * 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Every exception with its own stack: (IST index, stack name)
+const IST_STACKS: [(u16, &str); 3] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault stack"),
+    (NMI_IST_INDEX, "NMI stack"),
+    (MACHINE_CHECK_IST_INDEX, "machine check stack"),
+];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+//Used until the guarded stacks can be mapped, see `init_guarded_stacks`
+static mut BOOT_IST_STACKS: [[u8; IST_STACK_SIZE]; IST_STACKS.len()] =
+    [[0; IST_STACK_SIZE]; IST_STACKS.len()];
 
-            //stack end!
-            stack_start + STACK_SIZE
+//Mutable so the stacks can be replaced after boot, the CPU reads it on every interrupt
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
-        };
-        tss
-    };
-}
+/// Copy of the ring 0 stack in the TSS for the syscall entry, SYSCALL doesn't switch stacks
+pub(crate) static KERNEL_STACK_TOP: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
+    //user data comes before user code, the order SYSRET expects
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
+                user_code_selector,
+                user_data_selector,
                 tss_selector,
             },
         )
@@ -39,16 +59,68 @@ lazy_static! {
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
+    for (boot_stack, (index, _)) in unsafe { &*addr_of!(BOOT_IST_STACKS) }
+        .iter()
+        .zip(IST_STACKS)
+    {
+        let stack_start = VirtAddr::from_ptr(boot_stack);
+        set_interrupt_stack(index, stack_start + IST_STACK_SIZE);
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Moves the exception stacks to guarded kernel stacks, so that overflowing
+/// one of them faults instead of running into other memory. Needs the memory manager.
+pub fn init_guarded_stacks() {
+    for (index, name) in IST_STACKS {
+        let stack = stack::allocate(name, IST_STACK_SIZE as u64)
+            .expect("no memory for the exception stacks");
+        set_interrupt_stack(index, stack.top);
+    }
+}
+
+/// Code and data (stack) segment selectors for ring 3, their RPL is 3
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// Kernel code and data segment selectors
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// Sets the stack the CPU switches to when an interrupt, exception or system call arrives in
+/// ring 3. The scheduler points it at the kernel stack of every thread it switches to.
+pub fn set_kernel_stack(top: VirtAddr) {
+    interrupts::without_interrupts(|| unsafe {
+        (*addr_of_mut!(TSS)).privilege_stack_table[0] = top;
+        KERNEL_STACK_TOP.store(top.as_u64(), Ordering::Relaxed);
+    });
+}
+
+/// The stack pointer the CPU switches to for exceptions using IST entry `index`
+pub fn interrupt_stack(index: u16) -> VirtAddr {
+    unsafe { (*addr_of!(TSS)).interrupt_stack_table[index as usize] }
+}
+
+fn set_interrupt_stack(index: u16, top: VirtAddr) {
+    interrupts::without_interrupts(|| unsafe {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = top;
+    });
+}
@@ -1,16 +1,28 @@
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
 
 use crate::{
-    hlt_loop,
-    low_level::gdt,
-    println,
-    userspace::user_interface::{handle_keypress, handle_raw_keypress},
+    log,
+    low_level::{apic, exceptions, irq, serial},
+    task::keyboard,
+    userspace::user_interface::handle_keypress,
 };
 use pic8259::ChainedPics;
 use spin;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+/// OCW3, the next read of the command port returns the in-service register
+const READ_IN_SERVICE: u8 = 0x0B;
+/// The secondary PIC is chained to this input of the primary one
+const CASCADE_IRQ: u8 = 2;
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -18,15 +30,9 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::set_handlers(&mut idt);
+        irq::set_handlers(&mut idt);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -35,83 +41,157 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Registers the keyboard and serial console handlers
+pub fn init_input() {
+    irq::register_irq(KEYBOARD_IRQ, keyboard_interrupt).expect("keyboard IRQ is taken");
+    irq::register_irq(serial::COM1_IRQ, serial_interrupt).expect("serial IRQ is taken");
 }
 
-// new
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// Moves interrupt delivery over to the APICs if the machine has them, otherwise the PICs stay.
+/// Needs the heap and ACPI.
+pub fn init_apic() {
+    if apic::init() {
+        for irq in 0..irq::IRQ_COUNT as u8 {
+            if irq::has_handlers(irq) {
+                unmask_irq(irq);
+            }
+        }
+        log!("Using the APIC, local APIC ID {}", apic::local_apic_id());
+    } else {
+        log!("No APIC, staying with the 8259 PICs");
+    }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// Lets a legacy IRQ through, the BIOS may leave some of them (e.g. COM1) masked.
+/// With the APIC enabled this routes it through the I/O APIC.
+/// `irq::register_irq` does this for the first handler of an IRQ.
+pub fn unmask_irq(irq: u8) {
+    if apic::is_enabled() {
+        //the local APIC timer ticks on the same vector instead of the PIT
+        if irq != TIMER_IRQ {
+            apic::route_irq(irq, irq::vector_of(irq));
+        }
+        return;
     }
+    update_pic_masks(|primary, secondary| {
+        if irq < 8 {
+            (primary & !(1 << irq), secondary)
+        } else {
+            //the secondary PIC is chained through IRQ 2
+            (primary & !(1 << 2), secondary & !(1 << (irq - 8)))
+        }
+    });
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore
-            ));
+pub fn mask_irq(irq: u8) {
+    if apic::is_enabled() {
+        if irq != TIMER_IRQ {
+            apic::mask_irq(irq);
+        }
+        return;
     }
+    update_pic_masks(|primary, secondary| {
+        if irq < 8 {
+            (primary | 1 << irq, secondary)
+        } else {
+            (primary, secondary | 1 << (irq - 8))
+        }
+    });
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+fn update_pic_masks(f: impl FnOnce(u8, u8) -> (u8, u8)) {
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let [primary, secondary] = pics.read_masks();
+            let (primary, secondary) = f(primary, secondary);
+            pics.write_masks(primary, secondary);
+        }
+    });
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => handle_keypress(character),
-                DecodedKey::RawKey(keycode) => handle_raw_keypress(keycode),
-            }
+/// Acknowledges the interrupt at whichever controller delivered it. Nothing is sent for
+/// vectors that aren't in service, like spurious IRQs and interrupts raised with `int`,
+/// an EOI then would end whatever else is in service.
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        if apic::is_in_service(vector) {
+            apic::end_of_interrupt();
         }
+        return;
     }
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    let (primary, secondary) = pic_in_service();
+    let irq = vector.wrapping_sub(PIC_1_OFFSET);
+    let acknowledge = match irq {
+        0..8 => primary & 1 << irq != 0,
+        8..16 if secondary & 1 << (irq - 8) != 0 => true,
+        //a spurious IRQ 15 still went through the primary PIC, only that one gets the EOI
+        8..16 if primary & 1 << CASCADE_IRQ != 0 => {
+            unsafe {
+                PICS.lock()
+                    .notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ)
+            };
+            false
+        }
+        _ => false,
+    };
+    if acknowledge {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+/// Whether `irq` is a spurious IRQ 7 or 15, which the PICs raise when an interrupt
+/// went away before it could be delivered. Those aren't in service.
+pub fn is_spurious(irq: u8) -> bool {
+    if apic::is_enabled() {
+        //the local APIC has a vector of its own for them
+        return false;
+    }
+    let (primary, secondary) = pic_in_service();
+    match irq {
+        7 => primary & 1 << 7 == 0,
+        15 => secondary & 1 << 7 == 0,
+        _ => false,
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+/// In-service registers of the (primary, secondary) PIC, bit n is set while input n is handled
+fn pic_in_service() -> (u8, u8) {
+    interrupts::without_interrupts(|| {
+        //held so nothing else talks to the PICs in between
+        let _pics = PICS.lock();
+        let mut primary: Port<u8> = Port::new(PIC_1_COMMAND);
+        let mut secondary: Port<u8> = Port::new(PIC_2_COMMAND);
+        unsafe {
+            primary.write(READ_IN_SERVICE);
+            secondary.write(READ_IN_SERVICE);
+            (primary.read(), secondary.read())
+        }
+    })
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
+//Decoding happens in `task::keyboard::process_keypresses`, the handler only queues the scancode
+fn keyboard_interrupt(_irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    keyboard::add_scancode(scancode);
+}
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+//Bytes typed into the serial console are handled like keypresses
+fn serial_interrupt(_irq: u8) {
+    let mut received = [0u8; 16];
+    //The port lock has to be released before handling, as printing mirrors to serial
+    let count = serial::receive_into(&mut received);
+    for &byte in &received[..count] {
+        match byte {
+            b'\r' => handle_keypress('\n'),
+            0x7F => handle_keypress('\u{8}'),
+            byte if byte.is_ascii() => handle_keypress(byte as char),
+            _ => {}
+        }
     }
 }
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
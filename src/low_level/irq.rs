@@ -0,0 +1,154 @@
+//Hardware interrupt dispatch. Every legacy IRQ has a stub in the IDT that counts it,
+//calls the handlers drivers registered for it, sends the EOI and lets the scheduler preempt.
+//Spurious IRQs from the PICs are dropped before any of that.
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use crate::{
+    low_level::interrupts::{end_of_interrupt, is_spurious, mask_irq, unmask_irq, PIC_1_OFFSET},
+    task::thread,
+};
+
+/// IRQs 0 to 15, the lines of the two PICs
+pub const IRQ_COUNT: usize = 16;
+/// How many drivers can share one IRQ
+pub const MAX_HANDLERS_PER_IRQ: usize = 4;
+
+/// Gets the number of the IRQ it was called for
+pub type IrqHandler = fn(u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq,
+    AlreadyRegistered,
+    TooManyHandlers,
+}
+
+type HandlerSlots = [Option<IrqHandler>; MAX_HANDLERS_PER_IRQ];
+
+static HANDLERS: Mutex<[HandlerSlots; IRQ_COUNT]> =
+    Mutex::new([[None; MAX_HANDLERS_PER_IRQ]; IRQ_COUNT]);
+static COUNTS: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+
+/// Vector the IRQ is delivered on, by the PICs or the I/O APIC
+pub fn vector_of(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Points the IDT entry of every IRQ at its dispatch stub
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    for (irq, stub) in STUBS.into_iter().enumerate() {
+        idt[vector_of(irq as u8) as usize].set_handler_fn(stub);
+    }
+}
+
+/// Calls `handler` on every `irq`, the EOI is sent afterwards. The IRQ gets unmasked
+/// with its first handler. Several handlers per IRQ are called in the order they registered.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let first = interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slots = handlers.get_mut(irq as usize).ok_or(IrqError::InvalidIrq)?;
+        if slots
+            .iter()
+            .flatten()
+            .any(|&other| same_handler(other, handler))
+        {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let first = slots.iter().all(Option::is_none);
+        let slot = slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        *slot = Some(handler);
+        Ok(first)
+    })?;
+    if first {
+        unmask_irq(irq);
+    }
+    Ok(())
+}
+
+/// Removes a handler, the IRQ gets masked again when it was the last one.
+/// Returns false if it wasn't registered.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> bool {
+    let removed = interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slots = handlers.get_mut(irq as usize)?;
+        let index = slots
+            .iter()
+            .position(|slot| slot.is_some_and(|other| same_handler(other, handler)))?;
+        slots[index] = None;
+        //the free slot moves to the end, the others keep their order
+        slots[index..].rotate_left(1);
+        Some(slots[0].is_none())
+    });
+    match removed {
+        Some(last) => {
+            if last {
+                mask_irq(irq);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether any handler is registered for `irq`
+pub fn has_handlers(irq: u8) -> bool {
+    interrupts::without_interrupts(|| {
+        HANDLERS
+            .lock()
+            .get(irq as usize)
+            .is_some_and(|slots| slots.iter().any(Option::is_some))
+    })
+}
+
+/// How often `irq` fired since boot
+pub fn count(irq: u8) -> u64 {
+    COUNTS
+        .get(irq as usize)
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+fn same_handler(a: IrqHandler, b: IrqHandler) -> bool {
+    a as usize == b as usize
+}
+
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        end_of_interrupt(vector_of(irq));
+        return;
+    }
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    //copied out, so handlers are free to register or unregister
+    let handlers = HANDLERS.lock()[irq as usize];
+    for handler in handlers.into_iter().flatten() {
+        handler(irq);
+    }
+    end_of_interrupt(vector_of(irq));
+    //after the EOI, the next thread may run for a while before this one returns
+    thread::preempt();
+}
+
+macro_rules! irq_stubs {
+    ($($irq:literal => $stub:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $stub(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+        const STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] = [$($stub),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq_0, 1 => irq_1, 2 => irq_2, 3 => irq_3,
+    4 => irq_4, 5 => irq_5, 6 => irq_6, 7 => irq_7,
+    8 => irq_8, 9 => irq_9, 10 => irq_10, 11 => irq_11,
+    12 => irq_12, 13 => irq_13, 14 => irq_14, 15 => irq_15,
+}
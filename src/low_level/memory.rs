@@ -1,14 +1,105 @@
+use crate::low_level::{
+    allocator::{self, HeapStats},
+    boot_info,
+};
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    instructions::interrupts,
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable,
+        PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static FRAME_ALLOCATOR: Mutex<Option<PopFrameAllocator>> = Mutex::new(None);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Hands the page table and frame allocator over to the kernel after boot,
+/// so that e.g. the heap can map more pages when it runs out.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: PopFrameAllocator) {
+    interrupts::without_interrupts(|| {
+        *MAPPER.lock() = Some(mapper);
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+/// Runs `f` with the installed page table and frame allocator, None if they aren't installed yet.
+/// `f` must not allocate on the heap, as the heap calls this while it is locked.
+pub fn with_mapper_and_allocator<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut PopFrameAllocator) -> R,
+) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        Some(f(mapper.as_mut()?, frame_allocator.as_mut()?))
+    })
+}
+
+/// Like `with_mapper_and_allocator`, but gives up instead of waiting if either is locked.
+/// For the page fault handler, which may have interrupted their owner.
+pub fn try_with_mapper_and_allocator<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut PopFrameAllocator) -> R,
+) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.try_lock()?;
+        let mut frame_allocator = FRAME_ALLOCATOR.try_lock()?;
+        Some(f(mapper.as_mut()?, frame_allocator.as_mut()?))
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// Bytes of RAM in the memory map, see `boot_info::total_memory`
+    pub total_physical: u64,
+    pub usable_physical: u64,
+    pub frames_used: usize,
+    pub frames_free: usize,
+    pub heap: HeapStats,
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} MiB RAM, {} MiB usable, {} frames used, {} free, {}",
+            self.total_physical / (1024 * 1024),
+            self.usable_physical / (1024 * 1024),
+            self.frames_used,
+            self.frames_free,
+            self.heap
+        )
+    }
+}
+
+/// Current memory usage, the physical memory part stays zero until boot info and
+/// frame allocator are set up
+pub fn stats() -> MemoryStats {
+    let (total_physical, usable_physical) = match boot_info::get() {
+        Some(_) => (boot_info::total_memory(), boot_info::usable_memory()),
+        None => (0, 0),
+    };
+    let (frames_used, frames_free) = interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_ref().map_or((0, 0), |allocator| {
+            (allocator.used_frames(), allocator.free_frames())
+        })
+    });
+    MemoryStats {
+        total_physical,
+        usable_physical,
+        frames_used,
+        frames_free,
+        heap: allocator::heap_stats(),
+    }
+}
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
 
@@ -21,37 +112,184 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Physical frame allocator keeping one bit per frame (set = in use), the bitmap itself
+/// lives in the first usable region that is big enough for it.
 pub struct PopFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    bitmap: &'static mut [u64],
+    /// One bit per frame the allocator may hand out (set = usable), built from the memory map.
+    /// The frames holding the bitmaps aren't.
+    usable: &'static mut [u64],
+    /// Number of frames the bitmap covers, frames above the last usable region are left out
+    frame_count: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    /// Word of the bitmap the next search starts at
+    next_word: usize,
 }
 
 impl PopFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        PopFrameAllocator {
+    /// The memory map has to be valid and all usable frames in it unused, and the complete
+    /// physical memory has to be mapped at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let highest_address = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (highest_address / FRAME_SIZE) as usize;
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        //the usable mask comes right after the bitmap
+        let bitmap_bytes = (2 * words * core::mem::size_of::<u64>()) as u64;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE);
+
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable memory region can hold the frame bitmap")
+            .range
+            .start_addr();
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        bitmap.fill(u64::MAX);
+        let usable = core::slice::from_raw_parts_mut(bitmap_ptr.add(words), words);
+        usable.fill(0);
+
+        let mut allocator = PopFrameAllocator {
             memory_map,
-            next: 0,
+            bitmap,
+            usable,
+            frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            let first = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let last = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in first..last {
+                allocator.usable[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+                allocator.mark_free(index);
+            }
+            allocator.usable_frames += last - first;
+        }
+        let first_bitmap_frame = (bitmap_start / FRAME_SIZE) as usize;
+        for index in first_bitmap_frame..first_bitmap_frame + bitmap_frames as usize {
+            allocator.mark_used(index);
+            allocator.usable[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+        }
+        allocator
+    }
+
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+    /// Frames in usable regions, including the ones holding the bitmap
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+    fn is_usable(&self, index: usize) -> bool {
+        self.usable[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+    fn mark_used(&mut self, index: usize) {
+        debug_assert!(!self.is_used(index), "frame {:#x} is already in use", index);
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+    fn mark_free(&mut self, index: usize) {
+        debug_assert!(self.is_used(index), "frame {:#x} is already free", index);
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+        self.free_frames += 1;
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+    fn index_of(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    /// Allocates `count` physically contiguous frames, starting at a frame index that is a
+    /// multiple of `align` (in frames, e.g. 16 for 64 KiB), as DMA capable devices need it.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        if count == 0 || self.free_frames < count {
+            return None;
+        }
+        let align = align.max(1);
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            match (start..start + count)
+                .rev()
+                .find(|&index| self.is_used(index))
+            {
+                //continue after the used frame, rounded up to the alignment
+                Some(used) => start = (used + 1).div_ceil(align) * align,
+                None => {
+                    for index in start..start + count {
+                        self.mark_used(index);
+                    }
+                    return Some(PhysFrame::range(
+                        Self::frame_at(start),
+                        Self::frame_at(start + count),
+                    ));
+                }
+            }
         }
+        None
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// The frames have to be unused, e.g. from an earlier call to `allocate_contiguous`
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for PopFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let words = self.bitmap.len();
+        //start where the last free frame was found and wrap around once
+        let word = (0..words)
+            .map(|offset| (self.next_word + offset) % words)
+            .find(|&word| self.bitmap[word] != u64::MAX)?;
+        let index = word * BITS_PER_WORD + (!self.bitmap[word]).trailing_zeros() as usize;
+        if index >= self.frame_count {
+            return None;
+        }
+        self.mark_used(index);
+        self.next_word = word;
+        Some(Self::frame_at(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PopFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = Self::index_of(frame);
+        //freeing reserved memory or a frame twice would hand it out to two owners later
+        assert!(
+            index < self.frame_count && self.is_usable(index),
+            "{:?} is not managed",
+            frame
+        );
+        assert!(self.is_used(index), "{:?} is already free", frame);
+        self.mark_free(index);
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
     }
 }
@@ -1,5 +1,18 @@
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod boot_info;
+pub mod clock;
+pub mod exceptions;
 pub mod gdt;
 pub mod interrupts;
+pub mod irq;
 pub mod memory;
+pub mod power;
+pub mod rtc;
+pub mod serial;
+pub mod stack;
+pub mod syscall;
+pub mod timer;
 pub mod vga_buffer;
+pub mod vmm;
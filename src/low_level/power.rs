@@ -0,0 +1,180 @@
+//Turning the machine off and restarting it.
+//Both try the proper ACPI way first and fall back to what emulators and old PCs understand.
+use core::ptr;
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+use crate::{
+    hlt_loop,
+    low_level::{
+        acpi::{self, AddressSpace},
+        boot_info, timer,
+    },
+    userspace::logger::{self, Level},
+};
+
+// PM1 control register bits
+const SCI_ENABLE: u16 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+/// How long to wait for the firmware to switch to ACPI mode
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 300;
+/// How long each way of resetting gets before the next one is tried
+const RESET_WAIT_US: u64 = 50_000;
+/// How long the keyboard controller gets to take a command, it may not exist at all
+const KEYBOARD_CONTROLLER_TIMEOUT_US: u64 = 10_000;
+
+/// Ports that power off emulators: (port, value)
+const EMULATOR_SHUTDOWN_PORTS: [(u16, u16); 3] = [
+    (0x604, 0x2000),  //QEMU
+    (0xB004, 0x2000), //Bochs and older QEMU
+    (0x4004, 0x3400), //VirtualBox
+];
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const INPUT_BUFFER_FULL: u8 = 1 << 1;
+/// Pulses the CPU reset line
+const PULSE_RESET_LINE: u8 = 0xFE;
+
+// AML opcodes needed to find the \_S5_ package
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+/// Powers the machine off. Only returns if nothing worked, then the caller can still halt.
+pub fn shutdown() {
+    interrupts::disable();
+    if let Err(reason) = acpi_shutdown() {
+        //this can run from the panic screen, which must stay on the display
+        logger::log_quietly(
+            Level::Warn,
+            module_path!(),
+            file!(),
+            format_args!("ACPI shutdown failed: {}", reason),
+        );
+    }
+    for (port, value) in EMULATOR_SHUTDOWN_PORTS {
+        unsafe { Port::new(port).write(value) };
+    }
+}
+
+/// Restarts the machine, a triple fault makes sure this never returns
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(fadt) = acpi::fadt().filter(|fadt| fadt.supports_reset_register()) {
+        let register = fadt.reset_register.unwrap();
+        match register.address_space {
+            AddressSpace::Io => unsafe {
+                Port::new(register.address as u16).write(fadt.reset_value)
+            },
+            AddressSpace::Memory => unsafe {
+                let address = boot_info::physical_memory_offset() + register.address;
+                ptr::write_volatile(address.as_mut_ptr::<u8>(), fadt.reset_value);
+            },
+            AddressSpace::Other(_) => {}
+        }
+        timer::busy_wait_us(RESET_WAIT_US);
+    }
+
+    unsafe {
+        let mut status: Port<u8> = Port::new(KEYBOARD_CONTROLLER_STATUS);
+        //without a controller the port reads as all ones, the pulse is sent anyway then
+        let mut waited = 0;
+        while status.read() & INPUT_BUFFER_FULL != 0 && waited < KEYBOARD_CONTROLLER_TIMEOUT_US {
+            timer::busy_wait_us(10);
+            waited += 10;
+        }
+        Port::new(KEYBOARD_CONTROLLER_COMMAND).write(PULSE_RESET_LINE);
+    }
+    timer::busy_wait_us(RESET_WAIT_US);
+
+    //With an empty IDT any exception turns into a triple fault, which resets the CPU
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    hlt_loop();
+}
+
+fn acpi_shutdown() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    let (sleep_type_a, sleep_type_b) = s5_sleep_types().ok_or("no \\_S5_ in the DSDT")?;
+    if fadt.pm1a_control_block == 0 {
+        return Err("no PM1a control block");
+    }
+    let mut pm1a_control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    let mut pm1b_control: Port<u16> = Port::new(fadt.pm1b_control_block as u16);
+
+    unsafe {
+        if pm1a_control.read() & SCI_ENABLE == 0 {
+            if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+                return Err("can't switch to ACPI mode");
+            }
+            Port::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+            let mut waited = 0;
+            while pm1a_control.read() & SCI_ENABLE == 0 {
+                if waited == ACPI_ENABLE_TIMEOUT_MS {
+                    return Err("firmware didn't switch to ACPI mode");
+                }
+                timer::busy_wait_us(1000);
+                waited += 1;
+            }
+        }
+
+        pm1a_control.write((sleep_type_a as u16) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+        if fadt.pm1b_control_block != 0 {
+            pm1b_control.write((sleep_type_b as u16) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+        }
+    }
+    //powering off can take a moment
+    timer::busy_wait_us(RESET_WAIT_US);
+    Err("the machine is still running")
+}
+
+/// The SLP_TYPa and SLP_TYPb values for the soft off state.
+/// They are in the `\_S5_` package of the DSDT, which is found without a full AML interpreter.
+pub fn s5_sleep_types() -> Option<(u8, u8)> {
+    let aml = acpi::dsdt()?.bytes();
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+    //it has to be a `Name(_S5_, Package ...)`, optionally with a leading `\`
+    let is_name = match position {
+        0 => false,
+        1 => aml[0] == AML_NAME_OP,
+        _ => {
+            aml[position - 1] == AML_NAME_OP
+                || (aml[position - 1] == AML_ROOT_CHAR && aml[position - 2] == AML_NAME_OP)
+        }
+    };
+    let mut rest = aml.get(position + 4..)?;
+    if !is_name || *rest.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    //the package length takes 1 to 4 bytes, the top bits of the first one tell how many more
+    let length_bytes = ((rest.get(1)? >> 6) & 0b11) as usize + 1;
+    rest = rest.get(1 + length_bytes + 1..)?; //skip the element count too
+
+    let mut next_value = || -> Option<u8> {
+        let value = match *rest.first()? {
+            AML_BYTE_PREFIX => {
+                let value = *rest.get(1)?;
+                rest = &rest[2..];
+                value
+            }
+            value => {
+                rest = &rest[1..];
+                value
+            }
+        };
+        Some(value)
+    };
+    Some((next_value()?, next_value()?))
+}
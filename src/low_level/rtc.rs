@@ -0,0 +1,208 @@
+//Driver for the CMOS real-time clock, the battery backed clock that keeps the date while the machine is off.
+//It only has a resolution of a second, see `clock` for the time in between.
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use x86_64::instructions::{interrupts, port::Port};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY_OF_MONTH: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+/// Where most firmware keeps the century, ACPI can tell if it is somewhere else
+pub const DEFAULT_CENTURY_REGISTER: u8 = 0x32;
+
+// Status register bits
+const UPDATE_IN_PROGRESS: u8 = 0b1000_0000; //A
+const HOURS_24: u8 = 0b0000_0010; //B
+const BINARY_MODE: u8 = 0b0000_0100; //B
+/// Set in the hours register for PM times in 12 hour mode
+const HOUR_PM: u8 = 0b1000_0000;
+
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(DEFAULT_CENTURY_REGISTER);
+
+/// A date and time of day, in whatever time zone the RTC was set to (usually UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86_400
+            + self.hour as u64 * 3_600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(seconds: u64) -> Self {
+        let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+        let time = seconds % 86_400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3_600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Howard Hinnant's algorithms for the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Which CMOS register holds the century, 0 if there is none
+pub fn set_century_register(register: u8) {
+    CENTURY_REGISTER.store(register, Ordering::Relaxed);
+}
+
+/// The raw register values of one reading, compared to catch an update in between
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    RawTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY_OF_MONTH),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: if century_register != 0 {
+            read_register(century_register)
+        } else {
+            0
+        },
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Reads the current date and time from the RTC
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        //the clock may tick over while the registers are read one by one,
+        //so read until two readings in a row agree
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let status_b = read_register(STATUS_B);
+        decode(raw, status_b)
+    })
+}
+
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & BINARY_MODE != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & HOURS_24 == 0 {
+        //12 hour mode goes 12, 1, ..., 11
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let year = convert(raw.year) as u16;
+    let century = match convert(raw.century) {
+        century @ 19..=29 => century as u16,
+        //no usable century register, assume 1970 to 2069
+        _ if year < 70 => 20,
+        _ => 19,
+    };
+
+    DateTime {
+        year: century * 100 + year,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
@@ -0,0 +1,205 @@
+//Driver for the 16550 UART behind the COM1 serial port.
+//Run qemu with `-serial stdio` to see everything the kernel writes here.
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::{
+    interrupts,
+    port::{Port, PortReadOnly, PortWriteOnly},
+};
+
+pub const COM1: u16 = 0x3F8;
+/// IRQ line of COM1 on the legacy PIC
+pub const COM1_IRQ: u8 = 4;
+pub const DEFAULT_BAUD_RATE: u32 = 38_400;
+/// The UART divides this clock by the divisor latch to get the baud rate
+const UART_CLOCK: u32 = 115_200;
+/// How often the line status is polled before a byte is given up on
+const SEND_TIMEOUT: usize = 100_000;
+
+// Line control register bits
+const DATA_BITS_8: u8 = 0b0000_0011;
+const DIVISOR_LATCH_ACCESS: u8 = 0b1000_0000;
+// FIFO control: enable, clear both FIFOs, interrupt once 14 bytes are queued
+const FIFO_ENABLE_CLEAR_14: u8 = 0b1100_0111;
+// Modem control register bits
+const DATA_TERMINAL_READY: u8 = 0b0000_0001;
+const REQUEST_TO_SEND: u8 = 0b0000_0010;
+const AUXILIARY_OUTPUT_1: u8 = 0b0000_0100;
+const AUXILIARY_OUTPUT_2: u8 = 0b0000_1000; //gates the IRQ line on PC hardware
+const LOOPBACK: u8 = 0b0001_0000;
+// Interrupt enable register bits
+const RECEIVED_DATA_AVAILABLE: u8 = 0b0000_0001;
+// Line status register bits
+const DATA_READY: u8 = 0b0000_0001;
+const TRANSMITTER_HOLDING_EMPTY: u8 = 0b0010_0000;
+
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+
+pub struct SerialPort {
+    data: Port<u8>,
+    interrupt_enable: Port<u8>,
+    fifo_control: PortWriteOnly<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: PortReadOnly<u8>,
+    present: bool,
+}
+
+impl SerialPort {
+    /// The caller has to make sure that `base` is the I/O port of a 16550 compatible UART.
+    pub const unsafe fn new(base: u16) -> Self {
+        SerialPort {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: PortWriteOnly::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: PortReadOnly::new(base + 5),
+            present: false,
+        }
+    }
+
+    /// Sets the port up as 8N1 with the given baud rate, enables the FIFOs and the
+    /// "received data available" interrupt. If the UART fails its loopback self-test,
+    /// the port is marked as absent and every later write is dropped.
+    pub fn init(&mut self, baud_rate: u32) {
+        //the divisor latch is 16 bits wide, very low rates get the slowest it can do
+        let divisor = (UART_CLOCK / baud_rate.max(1)).clamp(1, u16::MAX as u32) as u16;
+        unsafe {
+            self.interrupt_enable.write(0x00);
+
+            self.line_control.write(DIVISOR_LATCH_ACCESS);
+            self.data.write(divisor as u8);
+            self.interrupt_enable.write((divisor >> 8) as u8);
+            self.line_control.write(DATA_BITS_8);
+
+            self.fifo_control.write(FIFO_ENABLE_CLEAR_14);
+
+            self.modem_control
+                .write(REQUEST_TO_SEND | AUXILIARY_OUTPUT_1 | AUXILIARY_OUTPUT_2 | LOOPBACK);
+            self.data.write(LOOPBACK_TEST_BYTE);
+            self.present = self.data.read() == LOOPBACK_TEST_BYTE;
+            if !self.present {
+                return;
+            }
+
+            self.modem_control
+                .write(DATA_TERMINAL_READY | REQUEST_TO_SEND | AUXILIARY_OUTPUT_2);
+            self.interrupt_enable.write(RECEIVED_DATA_AVAILABLE);
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    fn line_status(&mut self) -> u8 {
+        unsafe { self.line_status.read() }
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+        for _ in 0..SEND_TIMEOUT {
+            if self.line_status() & TRANSMITTER_HOLDING_EMPTY != 0 {
+                unsafe { self.data.write(byte) };
+                return;
+            }
+            core::hint::spin_loop();
+        }
+        //The transmitter never got ready, stop waiting on it on every byte
+        self.present = false;
+    }
+
+    /// Returns the next received byte, if there is one waiting in the FIFO
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.present && self.line_status() & DATA_READY != 0 {
+            Some(unsafe { self.data.read() })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        serial_port.init(DEFAULT_BAUD_RATE);
+        Mutex::new(serial_port)
+    };
+}
+
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+}
+
+/// Called from the COM1 interrupt handler, drains the receive FIFO into `buffer`
+/// and returns how many bytes were read.
+pub fn receive_into(buffer: &mut [u8]) -> usize {
+    let mut serial = SERIAL1.lock();
+    let mut count = 0;
+    while count < buffer.len() {
+        match serial.try_receive() {
+            Some(byte) => {
+                buffer[count] = byte;
+                count += 1;
+            }
+            None => break,
+        }
+    }
+    count
+}
+
+/// One received byte, without waiting for the port if someone else holds it.
+/// For polling with interrupts off, e.g. from the panic screen.
+pub fn try_receive_byte() -> Option<u8> {
+    SERIAL1.try_lock()?.try_receive()
+}
+
+/// Prints `args` unless someone else holds the port, then they are dropped
+pub fn try_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+    interrupts::without_interrupts(|| match SERIAL1.try_lock() {
+        Some(mut serial) => serial.write_fmt(args).is_ok(),
+        None => false,
+    })
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    interrupts::without_interrupts(|| {
+        SERIAL1
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+    });
+}
+
+/// serial_print!("text") - prints to the host through the COM1 serial port
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::low_level::serial::_print(format_args!($($arg)*)));
+}
+
+/// serial_println!("text") - prints to the host through the COM1 serial port, appending a newline
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
+}
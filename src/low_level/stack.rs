@@ -0,0 +1,211 @@
+//Kernel stacks live in their own area of the address space. Every stack gets a slot there
+//with unmapped pages below it, so running off its end faults instead of overwriting memory.
+use core::arch::asm;
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::low_level::{
+    memory,
+    vmm::{self, Backing},
+};
+
+/// Address space per stack, the stack sits at the top of it and the rest stays unmapped
+pub const SLOT_SIZE: u64 = 256 * 1024; // 256 KiB
+pub const MAX_STACKS: usize = 64;
+/// At least this much below every stack is never mapped
+pub const GUARD_SIZE: u64 = 4096;
+const PAGE_SIZE: u64 = 4096;
+const MAX_FOREIGN_STACKS: usize = 4;
+/// How far `register_boot_stack` walks the page table in either direction
+const MAX_BOOT_STACK_PAGES: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    /// Shown when the stack overflows
+    pub name: &'static str,
+    /// Start of the unmapped area below the stack, touching it means the stack overflowed
+    pub guard_start: VirtAddr,
+    /// Lowest mapped address
+    pub bottom: VirtAddr,
+    /// One past the highest address, what the stack pointer starts at
+    pub top: VirtAddr,
+}
+
+impl KernelStack {
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+    /// The unmapped page right below the stack
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - GUARD_SIZE)
+    }
+    pub fn guard_contains(&self, address: VirtAddr) -> bool {
+        (self.guard_start..self.bottom).contains(&address)
+    }
+}
+
+/// Start of the area holding all slots, reserved with the first stack
+static REGION: Once<VirtAddr> = Once::new();
+/// Allocated stacks by slot, followed by the ones registered from elsewhere (the boot stack)
+static STACKS: Mutex<[Option<KernelStack>; MAX_STACKS + MAX_FOREIGN_STACKS]> =
+    Mutex::new([None; MAX_STACKS + MAX_FOREIGN_STACKS]);
+
+/// Maps a new stack of at least `size` bytes, None if there is no free slot or memory.
+/// Needs the page table and frame allocator to be installed.
+pub fn allocate(name: &'static str, size: u64) -> Option<KernelStack> {
+    let size = size.max(1).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    if size > SLOT_SIZE - GUARD_SIZE {
+        return None;
+    }
+    let region = region()?;
+    interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let index = stacks[..MAX_STACKS].iter().position(Option::is_none)?;
+        let guard_start = region + index as u64 * SLOT_SIZE;
+        let top = guard_start + SLOT_SIZE;
+        let stack = KernelStack {
+            name,
+            guard_start,
+            bottom: top - size,
+            top,
+        };
+        let pages = Page::range(
+            Page::containing_address(stack.bottom),
+            Page::containing_address(stack.top),
+        );
+        memory::with_mapper_and_allocator(|mapper, frame_allocator| {
+            for (mapped, page) in pages.enumerate() {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                let result = frame_allocator.allocate_frame().and_then(|frame| {
+                    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                        Ok(flush) => {
+                            flush.flush();
+                            Some(())
+                        }
+                        Err(_) => {
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                            None
+                        }
+                    }
+                });
+                if result.is_none() {
+                    //give back what this stack got so far, the slot stays free
+                    for page in pages.take(mapped) {
+                        if let Ok((frame, flush)) = mapper.unmap(page) {
+                            flush.flush();
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                        }
+                    }
+                    return None;
+                }
+            }
+            Some(())
+        })??;
+        stacks[index] = Some(stack);
+        Some(stack)
+    })
+}
+
+/// Unmaps a stack from `allocate` and frees its slot. It must not be in use anymore.
+/// Returns false if it wasn't allocated here.
+pub fn free(stack: KernelStack) -> bool {
+    let freed = interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks[..MAX_STACKS]
+            .iter_mut()
+            .find(|slot| **slot == Some(stack))?;
+        *slot = None;
+        Some(())
+    });
+    if freed.is_none() {
+        return false;
+    }
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(stack.bottom),
+        Page::containing_address(stack.top),
+    );
+    memory::with_mapper_and_allocator(|mapper, frame_allocator| {
+        for page in pages {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    });
+    true
+}
+
+fn region() -> Option<VirtAddr> {
+    REGION
+        .try_call_once(|| {
+            vmm::allocate(
+                "kernel stacks",
+                SLOT_SIZE * MAX_STACKS as u64,
+                PageTableFlags::WRITABLE,
+                Backing::Anonymous { on_demand: false },
+            )
+            .map(|area| area.start)
+        })
+        .ok()
+        .copied()
+}
+
+/// Adds a stack that wasn't allocated here, so overflows of it get recognized too.
+/// Returns false if there is no room.
+pub fn register(stack: KernelStack) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        match stacks[MAX_STACKS..].iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(stack);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Registers the stack the bootloader set up, which `kernel_main` keeps running on.
+/// Its extent is found by walking the page table from the current stack pointer,
+/// the bootloader leaves the page below it unmapped.
+pub fn register_boot_stack() -> Option<KernelStack> {
+    let stack_pointer: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) stack_pointer, options(nomem, nostack)) };
+    let current = Page::<Size4KiB>::containing_address(VirtAddr::new(stack_pointer));
+    let (bottom, top) = memory::with_mapper_and_allocator(|mapper, _| {
+        let is_mapped = |page: Page| mapper.translate_page(page).is_ok();
+        let mut bottom = current;
+        while current - bottom < MAX_BOOT_STACK_PAGES && is_mapped(bottom - 1) {
+            bottom -= 1;
+        }
+        let mut top = current;
+        while top - current < MAX_BOOT_STACK_PAGES && is_mapped(top + 1) {
+            top += 1;
+        }
+        (bottom, top + 1)
+    })?;
+    let stack = KernelStack {
+        name: "boot stack",
+        guard_start: bottom.start_address() - GUARD_SIZE,
+        bottom: bottom.start_address(),
+        top: top.start_address(),
+    };
+    register(stack).then_some(stack)
+}
+
+/// The stack whose guard area `address` is in. Called from the page fault handler,
+/// so it gives up rather than wait for the lock.
+pub fn overflowed(address: VirtAddr) -> Option<KernelStack> {
+    STACKS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|stack| stack.guard_contains(address))
+        .copied()
+}
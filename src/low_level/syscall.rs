@@ -0,0 +1,90 @@
+//The SYSCALL/SYSRET entry into the kernel. A program puts the call number in rax and up to five
+//arguments in rdi, rsi, rdx, r10 and r8, the result comes back in rax. The CPU uses rcx and r11
+//for the return address and flags, the other argument registers come back zeroed.
+//What the calls do is up to `task::syscall`.
+use core::{arch::global_asm, sync::atomic::AtomicU64};
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use crate::{low_level::gdt, task::syscall};
+
+//Where the entry keeps the program's stack pointer until it is on the kernel stack.
+//Interrupts are off until then, so no other call can get in between.
+static USER_STACK_POINTER: AtomicU64 = AtomicU64::new(0);
+
+/// Enables SYSCALL and points it at the entry stub. Needs the GDT.
+pub fn init() {
+    let (user_code, user_data) = gdt::user_selectors();
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data)
+        .expect("the GDT doesn't have the order SYSCALL and SYSRET need");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    //cleared on entry, interrupts get enabled again once the stacks are switched
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Called by the entry stub with interrupts enabled, the result goes back in rax
+extern "C" fn handle_syscall(
+    number: u64,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+) -> u64 {
+    syscall::dispatch(number, [arg0, arg1, arg2, arg3, arg4])
+}
+
+extern "C" {
+    /// Where SYSCALL jumps to, never called from Rust
+    fn syscall_entry();
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_stack}], rsp",
+    "mov rsp, [rip + {kernel_stack}]",
+    "push qword ptr [rip + {user_stack}]",
+    //return address and flags of the program
+    "push rcx",
+    "push r11",
+    //keeps the stack aligned for the call
+    "sub rsp, 8",
+    //number and arguments moved to where the C calling convention has them
+    "mov r9, r8",
+    "mov r8, r10",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "sti",
+    "call {handler}",
+    "cli",
+    "add rsp, 8",
+    "pop r11",
+    "pop rcx",
+    //no kernel values go back to the program
+    "xor edi, edi",
+    "xor esi, esi",
+    "xor edx, edx",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "pop rsp",
+    "sysretq",
+    user_stack = sym USER_STACK_POINTER,
+    kernel_stack = sym gdt::KERNEL_STACK_TOP,
+    handler = sym handle_syscall,
+);
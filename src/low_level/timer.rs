@@ -0,0 +1,129 @@
+//Programmable interval timer (PIT) channel 0, it drives the timer interrupt
+//and everything here that counts time. Once the APIC is enabled the local APIC timer
+//ticks instead, the PIT keeps running for `busy_wait_us` and calibration.
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::{hlt, interrupts, port::Port};
+
+use crate::low_level::{apic, interrupts::TIMER_IRQ, irq};
+
+/// The PIT counts down at this rate
+const PIT_FREQUENCY: u32 = 1_193_182;
+pub const DEFAULT_TIMER_FREQUENCY: u32 = 1000; // Hz, one tick per millisecond
+const CHANNEL_0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+// Channel 0, low then high byte, mode 2 (rate generator), binary counting
+const SET_RATE_GENERATOR: u8 = 0b0011_0100;
+// Channel 0, latch the current count so it can be read consistently
+const LATCH_COUNT: u8 = 0b0000_0000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Until `init` the PIT runs at its power on default of ~18.2 Hz
+static FREQUENCY: AtomicU32 = AtomicU32::new(18);
+static DIVISOR: AtomicU32 = AtomicU32::new(65536);
+/// Length of one tick, `tick` adds it up so changing the frequency doesn't rescale the past
+static TICK_NS: AtomicU64 = AtomicU64::new(tick_ns(65536));
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the PIT, or the local APIC timer if the APIC is enabled, to fire `frequency`
+/// timer interrupts a second, as close as the divisor allows (19 Hz to ~1.19 MHz)
+pub fn init(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.max(1)).clamp(1, 65535);
+    interrupts::without_interrupts(|| {
+        let mut command: Port<u8> = Port::new(COMMAND);
+        let mut data: Port<u8> = Port::new(CHANNEL_0_DATA);
+        unsafe {
+            command.write(SET_RATE_GENERATOR);
+            data.write(divisor as u8);
+            data.write((divisor >> 8) as u8);
+        }
+        DIVISOR.store(divisor, Ordering::Relaxed);
+        let (frequency, tick_ns) = match apic::is_enabled() {
+            //the period the APIC timer was actually set to, calibrated against the PIT
+            true => {
+                let tick_ns = apic::start_timer(frequency).max(1);
+                ((1_000_000_000 / tick_ns) as u32, tick_ns)
+            }
+            false => (PIT_FREQUENCY / divisor, tick_ns(divisor)),
+        };
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+        TICK_NS.store(tick_ns, Ordering::Relaxed);
+    });
+    //calling init again only changes the frequency
+    let _ = irq::register_irq(TIMER_IRQ, |_| tick());
+}
+
+/// Timer interrupts per second
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Number of timer interrupts since the interrupts got enabled
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NS.fetch_add(TICK_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+const fn tick_ns(divisor: u32) -> u64 {
+    divisor as u64 * 1_000_000_000 / PIT_FREQUENCY as u64
+}
+
+/// Milliseconds since the interrupts got enabled, monotonic
+pub fn uptime_ms() -> u64 {
+    UPTIME_NS.load(Ordering::Relaxed) / 1_000_000
+}
+
+/// Rounded up, so waiting that many ticks is never shorter than `ms`
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * frequency() as u64).div_ceil(1000)
+}
+
+/// Halts until at least `ms` milliseconds have passed.
+/// With interrupts disabled no ticks arrive, so this falls back to `busy_wait_us`.
+pub fn sleep_ms(ms: u64) {
+    if !interrupts::are_enabled() {
+        busy_wait_us(ms * 1000);
+        return;
+    }
+    //the current tick is already partly over, so wait for one more
+    let deadline = ticks() + ms_to_ticks(ms) + 1;
+    while ticks() < deadline {
+        hlt();
+    }
+}
+
+/// Spins for at least `us` microseconds by watching the PIT count down.
+/// Doesn't depend on interrupts, so it also works inside handlers or before `init` is done.
+pub fn busy_wait_us(us: u64) {
+    let divisor = DIVISOR.load(Ordering::Relaxed) as u64;
+    let mut remaining = us * PIT_FREQUENCY as u64 / 1_000_000;
+    let mut last = read_count() as u64;
+    while remaining > 0 {
+        core::hint::spin_loop();
+        let now = read_count() as u64;
+        //the count goes down and starts over at the divisor
+        let elapsed = if now <= last {
+            last - now
+        } else {
+            last + divisor - now
+        };
+        remaining = remaining.saturating_sub(elapsed);
+        last = now;
+    }
+}
+
+fn read_count() -> u16 {
+    interrupts::without_interrupts(|| {
+        let mut command: Port<u8> = Port::new(COMMAND);
+        let mut data: Port<u8> = Port::new(CHANNEL_0_DATA);
+        unsafe {
+            command.write(LATCH_COUNT);
+            let low = data.read() as u16;
+            let high = data.read() as u16;
+            high << 8 | low
+        }
+    })
+}
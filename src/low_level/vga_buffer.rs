@@ -6,6 +6,7 @@ use x86_64::instructions::interrupts;
 use crate::low_level::vga_buffer::writer::Writer;
 mod buffer;
 mod writer;
+pub use buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -28,7 +29,7 @@ pub enum Color {
     White = 0x0F,
 }
 
-const VGA_BUFFER: usize = 0xb8000;
+pub(crate) const VGA_BUFFER: usize = 0xb8000;
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> =
         Mutex::new(Writer::new(0, Color::Yellow, Color::Black, VGA_BUFFER,));
@@ -40,6 +41,8 @@ pub enum CommandToWriter<'a> {
     Backspace,
     CursorBack,
     CursorFront,
+    /// Overwrites a whole row with the text in the current color, cut off at the screen width
+    WriteRow(usize, &'a str),
 }
 pub fn send_command_to_writer(command: CommandToWriter) {
     interrupts::without_interrupts(|| {
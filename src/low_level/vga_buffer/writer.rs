@@ -35,6 +35,7 @@ impl Writer {
             CommandToWriter::SetColor(foreground, background) => {
                 self.set_color(foreground, background)
             }
+            CommandToWriter::WriteRow(row, text) => self.write_row(row, text),
         }
     }
     fn move_cursor(&mut self, column_position: usize) {
@@ -89,6 +90,18 @@ impl Writer {
             self.buffer.chars[row][col] = blank;
         }
     }
+    fn write_row(&mut self, row: usize, text: &str) {
+        if row >= BUFFER_HEIGHT {
+            return;
+        }
+        self.clear_row(row);
+        for (col, byte) in text.bytes().take(BUFFER_WIDTH).enumerate() {
+            self.buffer.chars[row][col] = Char {
+                ascii_character: byte,
+                color_code: self.color_code,
+            };
+        }
+    }
     fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             self.write_byte(byte)
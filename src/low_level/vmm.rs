@@ -0,0 +1,473 @@
+//Kernel virtual address space. Every range the kernel uses is an area here, either at a
+//fixed address or handed out from the kernel window, so that no two of them overlap.
+//Anonymous areas can be backed lazily: nothing is mapped when they are reserved, and the
+//page fault handler maps a zeroed frame the first time a page is touched.
+use bootloader::bootinfo::MemoryMap;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, TranslateResult},
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+            PhysFrame, Size4KiB, Translate,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::low_level::{
+    memory::{self, PopFrameAllocator},
+    vga_buffer::VGA_BUFFER,
+};
+
+/// Areas without a fixed address are placed in here
+pub const KERNEL_SPACE_START: u64 = 0x_4444_0000_0000;
+pub const KERNEL_SPACE_END: u64 = 0x_7000_0000_0000;
+pub const MAX_AREAS: usize = 64;
+/// Areas handed out from the kernel window are at least this far apart, left unmapped
+const GAP_SIZE: u64 = 4096;
+const PAGE_SIZE: u64 = 4096;
+/// Set on the pages mapped here, so that nothing mapped by the bootloader gets unmapped
+const MAPPED_HERE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Where the memory of an area comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Fresh frames owned by the area, freed with it. With `on_demand` they are mapped
+    /// on first access, otherwise whoever owns the area maps them.
+    Anonymous { on_demand: bool },
+    /// Physical memory starting at the address that the area doesn't own, e.g. device registers
+    Mmio(PhysAddr),
+    /// Physical memory at the same address as the area, like what the bootloader identity maps
+    Identity,
+}
+
+/// Who hands the area out and takes it back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    /// Whoever reserved it, they release it
+    Caller,
+    /// `vmalloc`, only `vfree` takes it back
+    Vmalloc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// One past the last address
+    pub end: VirtAddr,
+    /// What pages get mapped with, PRESENT is added
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    pub owner: Owner,
+}
+
+impl Area {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+    /// Whether the access that caused a fault is allowed by the flags of the area
+    fn allows(&self, error_code: PageFaultErrorCode) -> bool {
+        let denied = |cause, needed: bool| error_code.contains(cause) && !needed;
+        !(denied(
+            PageFaultErrorCode::CAUSED_BY_WRITE,
+            self.flags.contains(PageTableFlags::WRITABLE),
+        ) || denied(
+            PageFaultErrorCode::INSTRUCTION_FETCH,
+            !self.flags.contains(PageTableFlags::NO_EXECUTE),
+        ) || denied(
+            PageFaultErrorCode::USER_MODE,
+            self.flags.contains(PageTableFlags::USER_ACCESSIBLE),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// Start or size isn't a multiple of the page size, or the size is zero
+    InvalidRange,
+    Overlaps,
+    TooManyAreas,
+    /// No gap in the kernel window is big enough
+    OutOfSpace,
+}
+
+static AREAS: Mutex<[Option<Area>; MAX_AREAS]> = Mutex::new([None; MAX_AREAS]);
+static DEMAND_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+/// Records what the bootloader mapped outside of the kernel image, so nothing gets placed
+/// on top of it: the physical memory and the VGA buffer
+pub fn init(physical_memory_offset: VirtAddr, memory_map: &MemoryMap) {
+    let physical_memory_size = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0)
+        .div_ceil(PAGE_SIZE)
+        * PAGE_SIZE;
+    insert(
+        "physical memory",
+        Some(physical_memory_offset),
+        physical_memory_size,
+        PageTableFlags::WRITABLE,
+        Backing::Mmio(PhysAddr::new(0)),
+        Owner::Caller,
+    )
+    .expect("physical memory mapping overlaps a kernel area");
+    insert(
+        "vga buffer",
+        Some(VirtAddr::new(VGA_BUFFER as u64)),
+        PAGE_SIZE,
+        PageTableFlags::WRITABLE,
+        Backing::Identity,
+        Owner::Caller,
+    )
+    .expect("vga buffer overlaps a kernel area");
+}
+
+/// Reserves `size` bytes at `start`, backed page by page on first access.
+/// The range must not be mapped by anything else.
+pub fn reserve(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<Area, VmmError> {
+    insert(
+        name,
+        Some(start),
+        size,
+        flags,
+        Backing::Anonymous { on_demand: true },
+        Owner::Caller,
+    )
+}
+
+/// Reserves `size` bytes somewhere in the kernel window. Nothing gets mapped,
+/// unless the area is backed on demand this is up to the caller.
+pub fn allocate(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+    backing: Backing,
+) -> Result<Area, VmmError> {
+    insert(name, None, size, flags, backing, Owner::Caller)
+}
+
+/// Maps `size` bytes of fresh, zeroed memory into the kernel window
+pub fn vmalloc(size: u64) -> Option<VirtAddr> {
+    let area = insert(
+        "vmalloc",
+        None,
+        size.max(1).div_ceil(PAGE_SIZE) * PAGE_SIZE,
+        PageTableFlags::WRITABLE,
+        Backing::Anonymous { on_demand: false },
+        Owner::Vmalloc,
+    )
+    .ok()?;
+    commit(&area).then_some(area.start)
+}
+
+/// Frees memory from `vmalloc`, false if `address` isn't the start of it
+pub fn vfree(address: VirtAddr) -> bool {
+    area_at(address).is_some_and(|area| area.owner == Owner::Vmalloc && area.start == address)
+        && release(address)
+}
+
+/// Maps `size` bytes of device registers at `physical` uncached into the kernel window.
+/// Returns the address `physical` ended up at.
+pub fn map_mmio(physical: PhysAddr, size: u64) -> Option<VirtAddr> {
+    let first = physical.align_down(PAGE_SIZE);
+    let size = (physical + size.max(1)).align_up(PAGE_SIZE) - first;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let area = allocate("mmio", size, flags, Backing::Mmio(first)).ok()?;
+    commit(&area).then(|| area.start + (physical - first))
+}
+
+/// Removes the area starting at `start` and unmaps it, frames it owns are freed.
+/// Returns false if there is no such area.
+pub fn release(start: VirtAddr) -> bool {
+    let Some(area) = remove(start) else {
+        return false;
+    };
+    memory::with_mapper_and_allocator(|mapper, frame_allocator| {
+        for page in area.pages() {
+            unmap_page(&area, page, mapper, frame_allocator);
+        }
+    });
+    true
+}
+
+/// Takes the area starting at `start` out of the list, its pages stay as they are
+fn remove(start: VirtAddr) -> Option<Area> {
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let slot = areas
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|area| area.start == start))?;
+        slot.take()
+    })
+}
+
+/// The area `address` is in
+pub fn area_at(address: VirtAddr) -> Option<Area> {
+    interrupts::without_interrupts(|| {
+        AREAS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|area| area.contains(address))
+            .copied()
+    })
+}
+
+/// How many pages got backed on demand since boot
+pub fn demand_faults() -> u64 {
+    DEMAND_FAULTS.load(Ordering::Relaxed)
+}
+
+/// Backs the page at `address` if it belongs to an area backed on demand and the access is
+/// allowed there. Returns false if the fault is a real one. Called from the page fault handler:
+/// the areas and page tables are only locked with interrupts off, so if they were on where the
+/// fault happened it can wait for the locks, otherwise it gives up rather than deadlock.
+pub fn handle_page_fault(
+    address: VirtAddr,
+    error_code: PageFaultErrorCode,
+    interrupts_were_enabled: bool,
+) -> bool {
+    //the page is there, the access itself isn't allowed
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let area = match interrupts_were_enabled {
+        true => area_at(address),
+        false => AREAS.try_lock().and_then(|areas| {
+            areas
+                .iter()
+                .flatten()
+                .find(|area| area.contains(address))
+                .copied()
+        }),
+    };
+    let Some(area) = area else {
+        return false;
+    };
+    if area.backing != (Backing::Anonymous { on_demand: true }) || !area.allows(error_code) {
+        return false;
+    }
+    let page = Page::containing_address(address);
+    let map = |mapper: &mut OffsetPageTable<'static>, frame_allocator: &mut PopFrameAllocator| {
+        map_page(&area, page, mapper, frame_allocator)
+    };
+    let mapped = match interrupts_were_enabled {
+        true => memory::with_mapper_and_allocator(map),
+        false => memory::try_with_mapper_and_allocator(map),
+    }
+    .unwrap_or(false);
+    if mapped {
+        DEMAND_FAULTS.fetch_add(1, Ordering::Relaxed);
+    }
+    mapped
+}
+
+/// Backs the pages of `start..start + len` that weren't accessed yet, so the kernel can use
+/// them without faulting. False if the range isn't in one area or a page couldn't be backed.
+pub fn populate(start: VirtAddr, len: u64) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let (Some(area), Some(end)) = (area_at(start), start.as_u64().checked_add(len)) else {
+        return false;
+    };
+    if end > area.end.as_u64() {
+        return false;
+    }
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    );
+    memory::with_mapper_and_allocator(|mapper, frame_allocator| {
+        pages.into_iter().all(|page| {
+            if mapper.translate_page(page).is_ok() {
+                return true;
+            }
+            let backed = area.backing == (Backing::Anonymous { on_demand: true })
+                && map_page(&area, page, mapper, frame_allocator);
+            if backed {
+                DEMAND_FAULTS.fetch_add(1, Ordering::Relaxed);
+            }
+            backed
+        })
+    })
+    .unwrap_or(false)
+}
+
+/// Adds an area at `start`, or at the first gap in the kernel window that fits
+fn insert(
+    name: &'static str,
+    start: Option<VirtAddr>,
+    size: u64,
+    flags: PageTableFlags,
+    backing: Backing,
+    owner: Owner,
+) -> Result<Area, VmmError> {
+    if size == 0 || !size.is_multiple_of(PAGE_SIZE) {
+        return Err(VmmError::InvalidRange);
+    }
+    if start.is_some_and(|start| !start.is_aligned(PAGE_SIZE)) {
+        return Err(VmmError::InvalidRange);
+    }
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let overlapping = |start: u64, end: u64| {
+            areas
+                .iter()
+                .flatten()
+                .find(|other| other.start.as_u64() < end && start < other.end.as_u64())
+                .copied()
+        };
+        let start = match start {
+            Some(start) => {
+                let end = start.as_u64().checked_add(size);
+                if overlapping(start.as_u64(), end.ok_or(VmmError::InvalidRange)?).is_some() {
+                    return Err(VmmError::Overlaps);
+                }
+                start.as_u64()
+            }
+            None => {
+                //every area in the way moves the candidate past its end, plus a gap
+                let mut candidate = KERNEL_SPACE_START;
+                loop {
+                    if size > KERNEL_SPACE_END - candidate {
+                        return Err(VmmError::OutOfSpace);
+                    }
+                    match overlapping(candidate, candidate + size + GAP_SIZE) {
+                        Some(other) => candidate = other.end.as_u64() + GAP_SIZE,
+                        None => break candidate,
+                    }
+                }
+            }
+        };
+        let area = Area {
+            name,
+            start: VirtAddr::try_new(start).map_err(|_| VmmError::InvalidRange)?,
+            end: VirtAddr::try_new(start + size).map_err(|_| VmmError::InvalidRange)?,
+            flags: flags | PageTableFlags::PRESENT,
+            backing,
+            owner,
+        };
+        let slot = areas
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmmError::TooManyAreas)?;
+        *slot = Some(area);
+        Ok(area)
+    })
+}
+
+/// Maps every page of a new area, it gets removed again if that fails
+pub fn commit(area: &Area) -> bool {
+    let mapped = memory::with_mapper_and_allocator(|mapper, frame_allocator| {
+        let mapped = area
+            .pages()
+            .all(|page| map_page(area, page, mapper, frame_allocator));
+        if !mapped {
+            //only undoes what got mapped here, pages that were there before are left alone
+            for page in area.pages() {
+                unmap_page(area, page, mapper, frame_allocator);
+            }
+        }
+        mapped
+    })
+    .unwrap_or(false);
+    if !mapped {
+        remove(area.start);
+    }
+    mapped
+}
+
+/// Maps one page of `area` to where its backing says, fresh frames get zeroed
+fn map_page(
+    area: &Area,
+    page: Page<Size4KiB>,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut PopFrameAllocator,
+) -> bool {
+    let frame = match area.backing {
+        Backing::Anonymous { .. } => {
+            let Some(frame) = frame_allocator.allocate_frame() else {
+                return false;
+            };
+            //zeroed through the physical memory mapping, the page may not be writable
+            let frame_ptr: *mut u8 =
+                (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+            unsafe { ptr::write_bytes(frame_ptr, 0, PAGE_SIZE as usize) };
+            frame
+        }
+        Backing::Mmio(physical) => {
+            PhysFrame::containing_address(physical + (page.start_address() - area.start))
+        }
+        Backing::Identity => {
+            PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64()))
+        }
+    };
+    match unsafe { mapper.map_to(page, frame, area.flags | MAPPED_HERE, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        //memory the area doesn't own may already be mapped there, e.g. by the bootloader,
+        //which is only fine if it is the same frame
+        Err(MapToError::PageAlreadyMapped(_))
+            if !matches!(area.backing, Backing::Anonymous { .. }) =>
+        {
+            mapper
+                .translate_page(page)
+                .is_ok_and(|mapped| mapped == frame)
+        }
+        Err(_) => {
+            if let Backing::Anonymous { .. } = area.backing {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            false
+        }
+    }
+}
+
+/// Unmaps one page of `area` if it was mapped here, frames the area owns are freed
+fn unmap_page(
+    area: &Area,
+    page: Page<Size4KiB>,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut PopFrameAllocator,
+) {
+    let mapped_here = matches!(
+        mapper.translate(page.start_address()),
+        TranslateResult::Mapped { flags, .. } if flags.contains(MAPPED_HERE)
+    );
+    if !mapped_here {
+        return;
+    }
+    if let Ok((frame, flush)) = mapper.unmap(page) {
+        flush.flush();
+        if let Backing::Anonymous { .. } = area.backing {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
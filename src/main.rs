@@ -1,11 +1,20 @@
 #![no_std] // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 use core::panic::PanicInfo;
 /// This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    hlt_loop();
+    popcorn::userspace::panic_screen::show_panic(info)
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
 }
 
 use bootloader::{entry_point, BootInfo};
@@ -14,7 +23,8 @@ use popcorn::{
     error, hlt_loop, init, log,
     low_level::vga_buffer::{send_command_to_writer, Color, CommandToWriter},
     print_with_colors, println,
-    userspace::output::MessageToVga,
+    task::{keyboard, Executor},
+    userspace::{logger, output::MessageToVga},
     warn,
 };
 entry_point!(kernel_main);
@@ -27,9 +37,15 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         MessageToVga::new(Color::LightBlue, Color::Black, "Popcorn Kernel!")
     );
     println!(); //Newline being other than black and white caused a bug with the cursor
+    logger::attach_console();
     log!("Initializing...");
     init(boot_info);
     log!("Initialized!");
 
-    hlt_loop();
+    #[cfg(test)]
+    test_main();
+
+    let mut executor = Executor::new();
+    executor.spawn(keyboard::process_keypresses());
+    executor.run();
 }
@@ -0,0 +1,193 @@
+//Polls tasks when they are woken, and halts the CPU until the next interrupt while none are.
+//Wakers only push onto a lock free queue, so interrupt handlers can wake tasks.
+//A task is in the queue at most once: its waker marks it queued, polling it clears that.
+use alloc::{collections::BTreeMap, rc::Rc, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+use super::{Task, TaskId};
+
+/// How many woken or newly spawned tasks can wait at once
+const QUEUE_SIZE: usize = 256;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    ready: Arc<ArrayQueue<TaskId>>,
+    /// Set when a task was woken while the ready queue was full
+    overflowed: Arc<AtomicBool>,
+    /// Tasks from a `Spawner`, added before the next round of polling
+    spawned: Rc<ArrayQueue<Task>>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            ready: Arc::new(ArrayQueue::new(QUEUE_SIZE)),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            spawned: Rc::new(ArrayQueue::new(QUEUE_SIZE)),
+            wakers: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) -> TaskId {
+        self.add(Task::new(future))
+    }
+
+    /// A handle that spawns onto this executor, for tasks that start other tasks
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: self.spawned.clone(),
+        }
+    }
+
+    /// Tasks that haven't finished yet
+    pub fn task_count(&self) -> usize {
+        self.tasks.len() + self.spawned.len()
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Runs until every task finished, tasks waiting for an interrupt keep it waiting as well
+    pub fn run_until_done(&mut self) {
+        while self.task_count() > 0 {
+            self.run_ready_tasks();
+            if self.task_count() > 0 {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    fn add(&mut self, task: Task) -> TaskId {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with ID {:?} already exists", id);
+        }
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            ready: self.ready.clone(),
+            overflowed: self.overflowed.clone(),
+        });
+        waker.wake_task();
+        self.wakers.insert(id, waker);
+        id
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while let Some(task) = self.spawned.pop() {
+            self.add(task);
+        }
+        loop {
+            while let Some(id) = self.ready.pop() {
+                self.poll_task(id);
+            }
+            if !self.overflowed.swap(false, Ordering::AcqRel) {
+                break;
+            }
+            //the wakes that didn't fit left their tasks marked queued, but not in the queue
+            let missed: Vec<TaskId> = self
+                .wakers
+                .values()
+                .filter(|waker| waker.queued.load(Ordering::Acquire))
+                .map(|waker| waker.id)
+                .collect();
+            for id in missed {
+                self.poll_task(id);
+            }
+        }
+    }
+
+    fn poll_task(&mut self, id: TaskId) {
+        //woken again after it finished
+        let (Some(task), Some(task_waker)) = (self.tasks.get_mut(&id), self.wakers.get(&id)) else {
+            return;
+        };
+        //before polling, so that a wake while it runs queues it again
+        task_waker.queued.store(false, Ordering::Release);
+        let waker = Waker::from(task_waker.clone());
+        let mut context = Context::from_waker(&waker);
+        if let Poll::Ready(()) = task.poll(&mut context) {
+            self.tasks.remove(&id);
+            self.wakers.remove(&id);
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        //an interrupt between the check and the hlt could wake a task that would then wait
+        //for the next interrupt, so they stay off until hlt
+        interrupts::disable();
+        if self.ready.is_empty()
+            && self.spawned.is_empty()
+            && !self.overflowed.load(Ordering::Acquire)
+        {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Spawns tasks onto an executor from inside its tasks, tasks stay on the CPU they started on
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Rc<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) -> TaskId {
+        let task = Task::new(future);
+        let id = task.id;
+        if self.spawned.push(task).is_err() {
+            panic!("spawn queue full");
+        }
+        id
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    /// In the ready queue, or about to be polled because it didn't fit
+    queued: AtomicBool,
+    ready: Arc<ArrayQueue<TaskId>>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl TaskWaker {
+    /// Called from interrupt handlers too, so it never panics
+    fn wake_task(&self) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if self.ready.push(self.id).is_err() {
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,120 @@
+//Keyboard input. The interrupt handler only queues the raw scancodes,
+//decoding and reacting to them happens in a task.
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+use crate::userspace::user_interface::{handle_keypress, handle_raw_keypress};
+
+/// Scancodes that can wait to be decoded, more get dropped
+pub const SCANCODE_QUEUE_SIZE: usize = 100;
+/// Typed characters that can wait for `pop_key`, more get dropped
+pub const KEY_QUEUE_SIZE: usize = 64;
+
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static KEY_QUEUE: OnceCell<ArrayQueue<char>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Called by the keyboard interrupt handler, must not block or allocate
+pub(crate) fn add_scancode(scancode: u8) {
+    match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => {
+            if queue.push(scancode).is_err() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            } else {
+                WAKER.wake();
+            }
+        }
+        //nobody listens yet
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Scancodes that got lost because the queue was full or there was no `ScancodeStream` yet
+pub fn dropped_scancodes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The oldest character typed since it was last called, for user programs.
+/// None if nothing was typed or keypresses aren't processed.
+pub fn pop_key() -> Option<char> {
+    KEY_QUEUE.try_get().ok()?.pop()
+}
+
+/// The scancodes from the keyboard, there can only be one
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE
+            .try_get()
+            .expect("scancode queue not initialized");
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+        //registered before checking again, so a scancode pushed in between still wakes the task
+        WAKER.register(context.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Decodes the keyboard input and hands the keys to the user interface
+pub async fn process_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let keys = KEY_QUEUE.get_or_init(|| ArrayQueue::new(KEY_QUEUE_SIZE));
+    let mut keyboard = Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        HandleControl::Ignore,
+    );
+    while let Some(scancode) = scancodes.next().await {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => {
+                        let _ = keys.push(character);
+                        handle_keypress(character)
+                    }
+                    DecodedKey::RawKey(keycode) => handle_raw_keypress(keycode),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//The system calls of user programs, see `low_level::syscall` for how they arrive.
+//Numbers and arguments are stable: programs are built against them. Results that are
+//errors are the negated `SyscallError` code, everything else is a successful result.
+use core::{slice, str};
+use x86_64::VirtAddr;
+
+use crate::{
+    low_level::{timer, vmm},
+    print,
+    task::{
+        keyboard, thread,
+        user::{self, ExitStatus},
+    },
+};
+
+/// write(text: *const u8, len) -> len. Prints UTF-8 text on the console.
+pub const WRITE: u64 = 0;
+/// read_key() -> character. The oldest typed character not read yet, 0 if there is none.
+pub const READ_KEY: u64 = 1;
+/// exit(code) -> never returns. Ends the program with `ExitStatus::Exited(code)`.
+pub const EXIT: u64 = 2;
+/// sleep(ms) -> 0
+pub const SLEEP: u64 = 3;
+/// get_time() -> milliseconds since boot
+pub const GET_TIME: u64 = 4;
+/// mmap(size) -> address. Maps zeroed, writable memory, the size is rounded up to whole pages.
+/// A task can have up to `user::MAX_MAPPED_BYTES` mapped.
+pub const MMAP: u64 = 5;
+/// munmap(address) -> 0. Unmaps memory mmap returned.
+pub const MUNMAP: u64 = 6;
+
+/// Longest text a single write can print
+pub const MAX_WRITE_LEN: u64 = 4096;
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    UnknownCall = 1,
+    /// A pointer argument isn't memory of the calling program
+    BadAddress = 2,
+    InvalidArgument = 3,
+    OutOfMemory = 4,
+}
+
+impl SyscallError {
+    /// How the error is returned in rax
+    pub fn to_result(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+
+    /// Tells errors from results, for code reading what a call returned
+    pub fn from_result(result: u64) -> Result<u64, SyscallError> {
+        match result.wrapping_neg() {
+            1 => Err(SyscallError::UnknownCall),
+            2 => Err(SyscallError::BadAddress),
+            3 => Err(SyscallError::InvalidArgument),
+            4 => Err(SyscallError::OutOfMemory),
+            _ => Ok(result),
+        }
+    }
+}
+
+/// Runs system call `number`, with interrupts enabled on the kernel stack of the calling thread
+pub(crate) fn dispatch(number: u64, args: [u64; 5]) -> u64 {
+    let result = match number {
+        WRITE => write(args[0], args[1]),
+        READ_KEY => Ok(keyboard::pop_key().map_or(0, u64::from)),
+        EXIT => exit(args[0]),
+        SLEEP => {
+            thread::sleep(args[0]);
+            Ok(0)
+        }
+        GET_TIME => Ok(timer::uptime_ms()),
+        MMAP => mmap(args[0]),
+        MUNMAP => munmap(args[0]),
+        _ => Err(SyscallError::UnknownCall),
+    };
+    result.unwrap_or_else(SyscallError::to_result)
+}
+
+fn write(text: u64, len: u64) -> Result<u64, SyscallError> {
+    if len > MAX_WRITE_LEN {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let bytes = user_slice(text, len)?;
+    let text = str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{}", text);
+    Ok(len)
+}
+
+fn exit(code: u64) -> Result<u64, SyscallError> {
+    user::exit_current(ExitStatus::Exited(code));
+    //only user tasks can exit
+    Err(SyscallError::InvalidArgument)
+}
+
+fn mmap(size: u64) -> Result<u64, SyscallError> {
+    let size = size
+        .checked_next_multiple_of(PAGE_SIZE)
+        .filter(|&size| size > 0)
+        .ok_or(SyscallError::InvalidArgument)?;
+    user::map_memory(size)
+        .map(VirtAddr::as_u64)
+        .ok_or(SyscallError::OutOfMemory)
+}
+
+fn munmap(address: u64) -> Result<u64, SyscallError> {
+    let address = VirtAddr::try_new(address).map_err(|_| SyscallError::BadAddress)?;
+    match user::unmap_memory(address) {
+        true => Ok(0),
+        false => Err(SyscallError::BadAddress),
+    }
+}
+
+/// `len` bytes of the calling program at `start`, after checking they are its memory
+fn user_slice(start: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if !user::can_access(start, len, false) {
+        return Err(SyscallError::BadAddress);
+    }
+    //pages the program didn't touch yet are backed now: a fault in the kernel that can't be
+    //handled would bring it down, rather than only the program
+    if !vmm::populate(VirtAddr::new(start), len) {
+        return Err(SyscallError::OutOfMemory);
+    }
+    Ok(unsafe { slice::from_raw_parts(start as *const u8, len as usize) })
+}
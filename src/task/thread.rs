@@ -0,0 +1,435 @@
+//Preemptive kernel threads. Every thread runs on its own guarded stack, and once the running
+//one used up its time slice the next ready thread gets the CPU, round robin.
+//A switch only saves the callee-saved registers: a preempted thread is switched away from at
+//the end of the timer interrupt, whose handler already saved all the others.
+use alloc::boxed::Box;
+use core::{
+    any::Any,
+    arch::global_asm,
+    marker::PhantomData,
+    ptr::{addr_of_mut, NonNull},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::low_level::{
+    gdt,
+    stack::{self, KernelStack},
+    timer,
+};
+
+pub const MAX_THREADS: usize = 32;
+pub const STACK_SIZE: u64 = 64 * 1024; // 64 KiB
+/// How long a thread runs before others get a turn
+pub const TIME_SLICE_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    TooManyThreads,
+    /// No stack could be mapped for it
+    NoStack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    Sleeping { until_ms: u64 },
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    /// Where `switch_context` left the stack while the thread isn't running
+    stack_pointer: u64,
+    /// None for the boot thread, which keeps the bootloader's stack
+    stack: Option<KernelStack>,
+    /// Freed with the thread, so it doesn't leak if the function never returns
+    entry: Option<OwnedEntry>,
+    /// What the function returned, until it is joined
+    result: Option<Box<dyn Any + Send>>,
+    /// Nobody can join it anymore, so it gets cleaned up once it finished
+    detached: bool,
+}
+
+/// The function of a thread
+trait Entry: Send {
+    /// Runs the function, only the first call does something
+    fn run(&mut self) -> Option<Box<dyn Any + Send>>;
+}
+
+impl<F, T> Entry for Option<F>
+where
+    F: FnOnce() -> T + Send,
+    T: Send + 'static,
+{
+    fn run(&mut self) -> Option<Box<dyn Any + Send>> {
+        let f = self.take()?;
+        Some(Box::new(f()))
+    }
+}
+
+/// A boxed `Entry` kept as a pointer, the thread runs it through a copy of that
+/// while the scheduler may borrow its `Thread`
+struct OwnedEntry(NonNull<dyn Entry>);
+
+//only the thread itself uses the entry, and it is only freed once the thread finished
+unsafe impl Send for OwnedEntry {}
+
+impl OwnedEntry {
+    fn new(entry: Box<dyn Entry>) -> Self {
+        OwnedEntry(NonNull::from(Box::leak(entry)))
+    }
+}
+
+impl Drop for OwnedEntry {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.0.as_ptr()) });
+    }
+}
+
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    current: usize,
+    next_id: u64,
+    slice_end_ms: u64,
+}
+
+impl Scheduler {
+    fn current(&mut self) -> &mut Thread {
+        self.threads[self.current]
+            .as_mut()
+            .expect("the current thread is gone")
+    }
+
+    /// Threads that are gone count as finished
+    fn is_finished(&self, id: ThreadId) -> bool {
+        !self
+            .threads
+            .iter()
+            .flatten()
+            .any(|thread| thread.id == id && thread.state != State::Finished)
+    }
+
+    fn can_run(&self, thread: &Thread, now_ms: u64) -> bool {
+        match thread.state {
+            State::Ready => true,
+            State::Sleeping { until_ms } => now_ms >= until_ms,
+            State::Joining(id) => self.is_finished(id),
+            State::Running | State::Finished => false,
+        }
+    }
+
+    /// The next thread after the current one that can run, the current one comes last
+    fn pick_next(&self) -> Option<usize> {
+        let now_ms = timer::uptime_ms();
+        (1..=MAX_THREADS)
+            .map(|offset| (self.current + offset) % MAX_THREADS)
+            .find(|&index| {
+                self.threads[index]
+                    .as_ref()
+                    .is_some_and(|thread| self.can_run(thread, now_ms))
+            })
+    }
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    threads: [const { None }; MAX_THREADS],
+    current: 0,
+    next_id: 1,
+    slice_end_ms: 0,
+});
+
+/// Turns the code that has been running since boot into the first thread
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.threads[0].is_none() {
+            scheduler.threads[0] = Some(Thread {
+                id: ThreadId(0),
+                name: "kernel main",
+                state: State::Running,
+                stack_pointer: 0,
+                stack: None,
+                entry: None,
+                result: None,
+                detached: true,
+            });
+            scheduler.current = 0;
+            scheduler.slice_end_ms = timer::uptime_ms() + TIME_SLICE_MS;
+        }
+    });
+}
+
+/// Starts `f` in a new thread, its result can be collected with `JoinHandle::join`
+pub fn spawn<T, F>(name: &'static str, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    init();
+    reap();
+    let entry = OwnedEntry::new(Box::new(Some(f)));
+    let stack = stack::allocate(name, STACK_SIZE).ok_or(SpawnError::NoStack)?;
+    let stack_pointer = unsafe { prepare_stack(&stack) };
+    let id = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let index = scheduler.threads.iter().position(Option::is_none)?;
+        let id = ThreadId(scheduler.next_id);
+        scheduler.next_id += 1;
+        scheduler.threads[index] = Some(Thread {
+            id,
+            name,
+            state: State::Ready,
+            stack_pointer,
+            stack: Some(stack),
+            entry: Some(entry),
+            result: None,
+            detached: false,
+        });
+        Some(id)
+    });
+    match id {
+        Some(id) => Ok(JoinHandle {
+            id,
+            result: PhantomData,
+        }),
+        None => {
+            stack::free(stack);
+            Err(SpawnError::TooManyThreads)
+        }
+    }
+}
+
+/// Lets the other ready threads run first
+pub fn yield_now() {
+    interrupts::without_interrupts(|| schedule(State::Ready));
+}
+
+/// Blocks the current thread for at least `ms` milliseconds, others run meanwhile
+pub fn sleep(ms: u64) {
+    //from a system call `ms` can be anything, u64::MAX sleeps forever
+    let until_ms = timer::uptime_ms().saturating_add(ms);
+    interrupts::without_interrupts(|| schedule(State::Sleeping { until_ms }));
+}
+
+/// Ends the current thread, like returning from its function.
+/// What the function has on the stack at that point is never dropped.
+pub fn exit() -> ! {
+    interrupts::disable();
+    schedule(State::Finished);
+    unreachable!("a finished thread was scheduled again");
+}
+
+pub fn current_id() -> ThreadId {
+    with_current(|thread| thread.id).unwrap_or(ThreadId(0))
+}
+
+/// Name of the running thread, for messages
+pub fn current_name() -> &'static str {
+    with_current(|thread| thread.name).unwrap_or("kernel main")
+}
+
+fn with_current<R>(f: impl FnOnce(&Thread) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads[scheduler.current].as_ref().map(f)
+    })
+}
+
+/// Threads that exist, including finished ones that weren't cleaned up yet
+pub fn thread_count() -> usize {
+    interrupts::without_interrupts(|| SCHEDULER.lock().threads.iter().flatten().count())
+}
+
+/// Called at the end of every interrupt, switches threads when the time slice is used up
+pub(crate) fn preempt() {
+    let due = SCHEDULER.try_lock().is_some_and(|scheduler| {
+        scheduler.threads[scheduler.current]
+            .as_ref()
+            .is_some_and(|thread| thread.state == State::Running)
+            && timer::uptime_ms() >= scheduler.slice_end_ms
+    });
+    if due {
+        schedule(State::Ready);
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: PhantomData<T>,
+}
+
+impl<T: 'static> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        interrupts::without_interrupts(|| SCHEDULER.lock().is_finished(self.id))
+    }
+
+    /// Waits for the thread to finish and returns what it returned,
+    /// None if it ended through `exit`
+    pub fn join(self) -> Option<T> {
+        let result = interrupts::without_interrupts(|| {
+            let finished = SCHEDULER.lock().is_finished(self.id);
+            if !finished {
+                schedule(State::Joining(self.id));
+            }
+            //the thread stays around until the handle is dropped
+            SCHEDULER
+                .lock()
+                .threads
+                .iter_mut()
+                .flatten()
+                .find(|thread| thread.id == self.id)
+                .and_then(|thread| thread.result.take())
+        });
+        result?.downcast().ok().map(|value| *value)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            if let Some(thread) = scheduler
+                .threads
+                .iter_mut()
+                .flatten()
+                .find(|thread| thread.id == self.id)
+            {
+                thread.detached = true;
+            }
+        });
+        reap();
+    }
+}
+
+/// Frees the finished threads nobody can join anymore
+fn reap() {
+    let mut finished = [const { None }; MAX_THREADS];
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        for (index, slot) in scheduler.threads.iter_mut().enumerate() {
+            let done = slot
+                .as_ref()
+                .is_some_and(|thread| thread.state == State::Finished && thread.detached);
+            //the current thread may still be on its stack
+            if done && index != current {
+                finished[index] = slot.take();
+            }
+        }
+    });
+    //the entry and result are dropped here too, with interrupts on
+    for thread in finished.into_iter().flatten() {
+        if let Some(stack) = thread.stack {
+            stack::free(stack);
+        }
+    }
+}
+
+/// Switches to the next thread that can run, the current one is left in `state`.
+/// Interrupts have to be disabled, they still are when the current thread continues.
+fn schedule(state: State) {
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current;
+    match scheduler.threads[current].as_mut() {
+        Some(thread) => thread.state = state,
+        //threads weren't set up yet
+        None => return,
+    }
+    let next = loop {
+        if let Some(next) = scheduler.pick_next() {
+            break next;
+        }
+        //nothing can run until an interrupt, e.g. the timer for a sleeping thread
+        drop(scheduler);
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+        scheduler = SCHEDULER.lock();
+    };
+    scheduler.slice_end_ms = timer::uptime_ms() + TIME_SLICE_MS;
+    scheduler.current = next;
+    scheduler.current().state = State::Running;
+    if next == current {
+        return;
+    }
+    let save_stack_pointer = match scheduler.threads[current].as_mut() {
+        Some(thread) => addr_of_mut!(thread.stack_pointer),
+        None => unreachable!(),
+    };
+    let next_thread = scheduler.current();
+    let stack_pointer = next_thread.stack_pointer;
+    //interrupts in ring 3 continue on the kernel stack of the thread that was running there
+    if let Some(stack) = next_thread.stack {
+        gdt::set_kernel_stack(stack.top);
+    }
+    drop(scheduler);
+    unsafe { switch_context(save_stack_pointer, stack_pointer) };
+}
+
+/// Lays out a new stack the way `switch_context` leaves one, so switching to it
+/// "returns" into `thread_start`
+unsafe fn prepare_stack(stack: &KernelStack) -> u64 {
+    let top = stack.top.as_mut_ptr::<u64>();
+    //`thread_start` never returns, the zero keeps the stack aligned like after a call
+    top.sub(1).write(0);
+    top.sub(2).write(thread_start as *const () as u64);
+    //rbp, rbx and r12 to r15
+    for index in 3..=8 {
+        top.sub(index).write(0);
+    }
+    top.sub(8) as u64
+}
+
+/// Where every new thread starts, switched to with interrupts off
+extern "C" fn thread_start() -> ! {
+    let entry = SCHEDULER
+        .lock()
+        .current()
+        .entry
+        .as_ref()
+        .map(|entry| entry.0);
+    interrupts::enable();
+    if let Some(entry) = entry {
+        //nothing else touches the entry while the thread runs
+        let result = unsafe { (*entry.as_ptr()).run() };
+        interrupts::without_interrupts(|| SCHEDULER.lock().current().result = result);
+    }
+    exit();
+}
+
+extern "C" {
+    /// Saves the callee-saved registers and the stack pointer into `save_stack_pointer`,
+    /// then continues with the thread whose stack pointer is `stack_pointer`
+    fn switch_context(save_stack_pointer: *mut u64, stack_pointer: u64);
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
@@ -0,0 +1,357 @@
+//User programs, running in ring 3. Each one gets its own kernel thread, which maps the program
+//and its stack as user accessible areas and enters it with iretq. The program only comes back
+//into the kernel through system calls, interrupts and exceptions, faults in it end the task.
+use core::{arch::asm, ptr};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageTableFlags, Translate},
+    VirtAddr,
+};
+
+use crate::{
+    low_level::{
+        gdt, memory,
+        vmm::{self, Backing},
+    },
+    task::thread::{self, JoinHandle, SpawnError, ThreadId},
+    warn,
+};
+
+pub const MAX_USER_TASKS: usize = 16;
+/// Backed on demand, so only the part that gets used takes memory
+pub const USER_STACK_SIZE: u64 = 64 * 1024; // 64 KiB
+/// Areas a task can map with the mmap system call at once
+pub const MAX_MAPPINGS: usize = 8;
+/// How much memory a task can map with the mmap system call at once
+pub const MAX_MAPPED_BYTES: u64 = 4 * 1024 * 1024; // 4 MiB
+const PAGE_SIZE: u64 = 4096;
+/// Interrupts enabled, bit 1 is always set
+const USER_RFLAGS: u64 = 0x202;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(u64),
+    /// Ended by the exception with this name
+    Killed(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    TooManyTasks,
+    /// The program is empty
+    NoCode,
+    OutOfMemory,
+    Spawn(SpawnError),
+}
+
+struct Slot {
+    /// Set by the task itself before it enters ring 3
+    thread: Option<ThreadId>,
+    code: VirtAddr,
+    stack: VirtAddr,
+    /// Starts of the areas mapped with the mmap system call
+    mappings: [Option<VirtAddr>; MAX_MAPPINGS],
+    /// Size of all of them together
+    mapped_bytes: u64,
+    status: Option<ExitStatus>,
+    /// The `UserTask` is gone, nobody waits for the status
+    detached: bool,
+}
+
+impl Slot {
+    /// Whether the area starting at `start` belongs to this task
+    fn owns(&self, start: VirtAddr) -> bool {
+        self.code == start || self.stack == start || self.mappings.contains(&Some(start))
+    }
+}
+
+static TASKS: Mutex<[Option<Slot>; MAX_USER_TASKS]> = Mutex::new([const { None }; MAX_USER_TASKS]);
+
+/// A running user program
+pub struct UserTask {
+    slot: usize,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl UserTask {
+    pub fn thread_id(&self) -> ThreadId {
+        self.handle
+            .as_ref()
+            .expect("user task without a thread")
+            .id()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+
+    /// Waits for the program to end
+    pub fn wait(mut self) -> ExitStatus {
+        if let Some(handle) = self.handle.take() {
+            handle.join();
+        }
+        interrupts::without_interrupts(|| {
+            let mut tasks = TASKS.lock();
+            let status = tasks[self.slot].as_ref().and_then(|slot| slot.status);
+            tasks[self.slot] = None;
+            status
+        })
+        .expect("user task ended without a status")
+    }
+}
+
+impl Drop for UserTask {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut tasks = TASKS.lock();
+            if let Some(slot) = tasks[self.slot].as_mut() {
+                if slot.status.is_some() {
+                    tasks[self.slot] = None;
+                } else {
+                    slot.detached = true;
+                }
+            }
+        });
+    }
+}
+
+/// Loads `code` into user memory and starts running it in ring 3 from its first byte.
+/// The code must be position independent.
+pub fn spawn(name: &'static str, code: &[u8]) -> Result<UserTask, UserError> {
+    if code.is_empty() {
+        return Err(UserError::NoCode);
+    }
+    let code_area = vmm::allocate(
+        name,
+        (code.len() as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE,
+        PageTableFlags::USER_ACCESSIBLE,
+        Backing::Anonymous { on_demand: false },
+    )
+    .map_err(|_| UserError::OutOfMemory)?;
+    //a failed commit already removed the area
+    if !vmm::commit(&code_area) {
+        return Err(UserError::OutOfMemory);
+    }
+    if !copy_to_user(code_area.start, code) {
+        vmm::release(code_area.start);
+        return Err(UserError::OutOfMemory);
+    }
+    let Ok(stack_area) = vmm::allocate(
+        name,
+        USER_STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        Backing::Anonymous { on_demand: true },
+    ) else {
+        vmm::release(code_area.start);
+        return Err(UserError::OutOfMemory);
+    };
+    let release_areas = || {
+        vmm::release(code_area.start);
+        vmm::release(stack_area.start);
+    };
+
+    let slot = interrupts::without_interrupts(|| {
+        let mut tasks = TASKS.lock();
+        let index = tasks.iter().position(Option::is_none)?;
+        tasks[index] = Some(Slot {
+            thread: None,
+            code: code_area.start,
+            stack: stack_area.start,
+            mappings: [None; MAX_MAPPINGS],
+            mapped_bytes: 0,
+            status: None,
+            detached: false,
+        });
+        Some(index)
+    });
+    let Some(slot) = slot else {
+        release_areas();
+        return Err(UserError::TooManyTasks);
+    };
+
+    let entry = code_area.start;
+    let stack_top = stack_area.end;
+    let handle = thread::spawn(name, move || {
+        interrupts::without_interrupts(|| {
+            if let Some(slot) = TASKS.lock()[slot].as_mut() {
+                slot.thread = Some(thread::current_id());
+            }
+        });
+        unsafe { enter_user_mode(entry, stack_top) }
+    });
+    match handle {
+        Ok(handle) => Ok(UserTask {
+            slot,
+            handle: Some(handle),
+        }),
+        Err(error) => {
+            interrupts::without_interrupts(|| TASKS.lock()[slot] = None);
+            release_areas();
+            Err(UserError::Spawn(error))
+        }
+    }
+}
+
+/// Ends the user task running on this thread with `status` and frees its memory.
+/// Returns if the current thread doesn't run a user program.
+pub(crate) fn exit_current(status: ExitStatus) {
+    let current = thread::current_id();
+    let areas = interrupts::without_interrupts(|| {
+        let mut tasks = TASKS.lock();
+        let index = tasks.iter().position(|slot| {
+            slot.as_ref()
+                .is_some_and(|slot| slot.thread == Some(current))
+        })?;
+        let slot = tasks[index].as_mut()?;
+        let mut areas = [None; MAX_MAPPINGS + 2];
+        areas[0] = Some(slot.code);
+        areas[1] = Some(slot.stack);
+        for (area, mapping) in areas[2..].iter_mut().zip(slot.mappings.iter_mut()) {
+            *area = mapping.take();
+        }
+        if slot.detached {
+            tasks[index] = None;
+        } else {
+            slot.status = Some(status);
+        }
+        Some(areas)
+    });
+    let Some(areas) = areas else {
+        return;
+    };
+    for start in areas.into_iter().flatten() {
+        vmm::release(start);
+    }
+    thread::exit();
+}
+
+/// Ends the user task on this thread because of an exception in it.
+/// Returns if the current thread doesn't run a user program.
+pub(crate) fn kill_current(exception: &'static str, reason: core::fmt::Arguments) {
+    warn!("user task {} killed: {}", thread::current_name(), reason);
+    exit_current(ExitStatus::Killed(exception));
+}
+
+/// Maps `size` bytes of zeroed, writable memory for the user task on this thread,
+/// backed on demand and freed when the task ends. `size` must be a multiple of the page size.
+/// Fails if the task would have more than MAX_MAPPED_BYTES mapped.
+pub(crate) fn map_memory(size: u64) -> Option<VirtAddr> {
+    let index = with_current_slot(|slot| {
+        let within_limit = slot
+            .mapped_bytes
+            .checked_add(size)
+            .is_some_and(|total| total <= MAX_MAPPED_BYTES);
+        slot.mappings
+            .iter()
+            .position(Option::is_none)
+            .filter(|_| within_limit)
+    })??;
+    let area = vmm::allocate(
+        thread::current_name(),
+        size,
+        PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        Backing::Anonymous { on_demand: true },
+    )
+    .ok()?;
+    with_current_slot(|slot| {
+        slot.mappings[index] = Some(area.start);
+        slot.mapped_bytes += size;
+    });
+    Some(area.start)
+}
+
+/// Unmaps an area `map_memory` returned, false if it isn't one of the current task's
+pub(crate) fn unmap_memory(start: VirtAddr) -> bool {
+    let size = vmm::area_at(start).map_or(0, |area| area.size());
+    let mapping = with_current_slot(|slot| {
+        let mapping = slot
+            .mappings
+            .iter_mut()
+            .find(|mapping| **mapping == Some(start))?
+            .take();
+        slot.mapped_bytes = slot.mapped_bytes.saturating_sub(size);
+        mapping
+    });
+    mapping.flatten().is_some_and(vmm::release)
+}
+
+/// Whether the user task on this thread may access `len` bytes at `start`:
+/// they have to lie in one of its own areas, which has to be writable for `write`
+pub(crate) fn can_access(start: u64, len: u64, write: bool) -> bool {
+    let (Ok(address), Some(end)) = (VirtAddr::try_new(start), start.checked_add(len)) else {
+        return false;
+    };
+    let Some(area) = vmm::area_at(address) else {
+        return false;
+    };
+    with_current_slot(|slot| slot.owns(area.start)).unwrap_or(false)
+        && end <= area.end.as_u64()
+        && (!write || area.flags.contains(PageTableFlags::WRITABLE))
+}
+
+fn with_current_slot<R>(f: impl FnOnce(&mut Slot) -> R) -> Option<R> {
+    let current = thread::current_id();
+    interrupts::without_interrupts(|| {
+        TASKS
+            .lock()
+            .iter_mut()
+            .flatten()
+            .find(|slot| slot.thread == Some(current))
+            .map(f)
+    })
+}
+
+/// Copies `bytes` to the start of a mapped user area through the physical memory mapping,
+/// the pages aren't writable from its virtual address
+fn copy_to_user(start: VirtAddr, bytes: &[u8]) -> bool {
+    memory::with_mapper_and_allocator(|mapper, _| {
+        for (index, chunk) in bytes.chunks(PAGE_SIZE as usize).enumerate() {
+            let Some(physical) = mapper.translate_addr(start + index as u64 * PAGE_SIZE) else {
+                return false;
+            };
+            let destination: *mut u8 = (mapper.phys_offset() + physical.as_u64()).as_mut_ptr();
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), destination, chunk.len()) };
+        }
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Drops to ring 3 at `entry` with the stack pointer at `stack_top`. The kernel frames of the
+/// current thread are abandoned, interrupts from the program start at the top of its stack.
+unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let (code_selector, data_selector) = gdt::user_selectors();
+    //the frame iretq pops, then no kernel values are left in the registers
+    asm!(
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        ss = in(reg) data_selector.0 as u64,
+        rsp = in(reg) stack_top.as_u64(),
+        rflags = in(reg) USER_RFLAGS,
+        cs = in(reg) code_selector.0 as u64,
+        rip = in(reg) entry.as_u64(),
+        options(noreturn)
+    )
+}
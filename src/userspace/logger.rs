@@ -0,0 +1,309 @@
+//Kernel log: every message goes through here, gets filtered by level, stored in a
+//ring buffer (like dmesg) and then printed on screen and mirrored to serial.
+//Messages logged before the console is attached are kept and replayed on attach.
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    low_level::{
+        clock,
+        rtc::DateTime,
+        serial, timer,
+        vga_buffer::{send_command_to_writer, Color, CommandToWriter},
+    },
+    userspace::output::{serial_mirroring, FixedString},
+};
+
+/// How many records the ring buffer keeps before overwriting the oldest one
+pub const LOG_CAPACITY: usize = 64;
+/// Longer messages are cut off
+pub const MESSAGE_CAPACITY: usize = 96;
+const MAX_MODULE_FILTERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Trace = 0,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Level::Trace,
+            1 => Level::Debug,
+            2 => Level::Info,
+            3 => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+    /// Background of the file name badge
+    fn badge_color(self) -> Color {
+        match self {
+            Level::Trace => Color::DarkGrey,
+            Level::Debug => Color::LightCyan,
+            Level::Info => Color::LightGreen,
+            Level::Warn => Color::Yellow,
+            Level::Error => Color::LightRed,
+        }
+    }
+    fn text_color(self) -> Color {
+        match self {
+            Level::Trace => Color::DarkGrey,
+            Level::Debug => Color::LighGrey,
+            Level::Info => Color::White,
+            Level::Warn => Color::Yellow,
+            Level::Error => Color::LightRed,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub level: Level,
+    /// Milliseconds since boot at the time of logging
+    pub uptime_ms: u64,
+    pub module: &'static str,
+    pub file: &'static str,
+    message: FixedString<MESSAGE_CAPACITY>,
+}
+
+impl Record {
+    fn new(level: Level, module: &'static str, file: &'static str, args: fmt::Arguments) -> Self {
+        let mut message = FixedString::new();
+        let _ = message.write_fmt(args);
+        Record {
+            level,
+            uptime_ms: timer::uptime_ms(),
+            module,
+            file,
+            message,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] [{}] [{}] {}",
+            Timestamp(self.uptime_ms),
+            self.level.name(),
+            self.file,
+            self.message()
+        )
+    }
+}
+
+/// Time of day once the wall clock is set, seconds since boot before that
+struct Timestamp(u64);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uptime_ms = self.0;
+        match clock::unix_time_ms_at(uptime_ms) {
+            Some(unix_ms) => {
+                let time = DateTime::from_unix(unix_ms / 1000);
+                write!(
+                    f,
+                    "{:02}:{:02}:{:02}.{:03}",
+                    time.hour,
+                    time.minute,
+                    time.second,
+                    unix_ms % 1000
+                )
+            }
+            None => write!(f, "{:>5}.{:03}", uptime_ms / 1000, uptime_ms % 1000),
+        }
+    }
+}
+
+struct LogBuffer {
+    records: [Option<Record>; LOG_CAPACITY],
+    /// Index the next record gets written to
+    next: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        const EMPTY: Option<Record> = None;
+        LogBuffer {
+            records: [EMPTY; LOG_CAPACITY],
+            next: 0,
+        }
+    }
+    fn push(&mut self, record: Record) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % LOG_CAPACITY;
+    }
+    /// Records from oldest to newest
+    fn iter(&self) -> impl DoubleEndedIterator<Item = &Record> {
+        let (newer, older) = self.records.split_at(self.next);
+        older.iter().chain(newer.iter()).flatten()
+    }
+}
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer::new());
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static MODULE_FILTERS: Mutex<[Option<(&str, Level)>; MAX_MODULE_FILTERS]> =
+    Mutex::new([None; MAX_MODULE_FILTERS]);
+static CONSOLE_ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Messages below this level are dropped, unless a module filter says otherwise
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Overrides the level for every module whose path starts with `module`,
+/// e.g. set_module_level("popcorn::low_level::memory", Level::Trace).
+/// The longest matching prefix wins. Returns false if there is no room for another filter.
+pub fn set_module_level(module: &'static str, level: Level) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut filters = MODULE_FILTERS.lock();
+        if let Some(filter) = filters
+            .iter_mut()
+            .flatten()
+            .find(|(prefix, _)| *prefix == module)
+        {
+            filter.1 = level;
+            return true;
+        }
+        match filters.iter_mut().find(|filter| filter.is_none()) {
+            Some(slot) => {
+                *slot = Some((module, level));
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+pub fn clear_module_levels() {
+    interrupts::without_interrupts(|| *MODULE_FILTERS.lock() = [None; MAX_MODULE_FILTERS]);
+}
+
+/// Whether a message of `level` from `module` would be logged
+pub fn enabled(level: Level, module: &str) -> bool {
+    let module_level = interrupts::without_interrupts(|| {
+        MODULE_FILTERS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|(prefix, _)| module.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+    });
+    level >= module_level.unwrap_or_else(max_level)
+}
+
+/// Use the log!/warn!/error!/... macros instead of calling this directly
+pub fn log(level: Level, module: &'static str, file: &'static str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let record = Record::new(level, module, file, args);
+    interrupts::without_interrupts(|| LOG_BUFFER.lock().push(record));
+    if CONSOLE_ATTACHED.load(Ordering::Relaxed) {
+        print_to_console(&record);
+    }
+    if serial_mirroring() {
+        serial::_print(format_args!("{}\n", record));
+    }
+}
+
+/// Like `log`, but never draws on the screen and doesn't wait for locks, a record that
+/// can't get into the ring buffer or out on serial right away is dropped there.
+/// For code that can run while the panic screen is up.
+pub fn log_quietly(level: Level, module: &'static str, file: &'static str, args: fmt::Arguments) {
+    let record = Record::new(level, module, file, args);
+    interrupts::without_interrupts(|| {
+        if let Some(mut buffer) = LOG_BUFFER.try_lock() {
+            buffer.push(record);
+        }
+    });
+    if serial_mirroring() {
+        serial::try_print(format_args!("{}\n", record));
+    }
+}
+
+/// Marks the screen as ready and replays everything that was logged before it was
+pub fn attach_console() {
+    if !CONSOLE_ATTACHED.swap(true, Ordering::Relaxed) {
+        replay();
+    }
+}
+
+/// Prints every record still in the ring buffer on screen
+pub fn replay() {
+    for_each_recent(LOG_CAPACITY, print_to_console);
+}
+
+/// Calls `f` with the last `count` records, oldest first.
+/// Records are copied out one at a time, so `f` is free to log or print.
+pub fn for_each_recent(count: usize, mut f: impl FnMut(&Record)) {
+    let available = interrupts::without_interrupts(|| LOG_BUFFER.lock().iter().count());
+    for back in (0..count.min(available)).rev() {
+        let record =
+            interrupts::without_interrupts(|| LOG_BUFFER.lock().iter().rev().nth(back).copied());
+        if let Some(record) = record {
+            f(&record);
+        }
+    }
+}
+
+/// Like `for_each_recent`, but for the panic screen: the code that panicked may hold
+/// the buffer, so this gives up and returns false instead of waiting for it.
+/// `f` runs with the buffer locked and must not log.
+pub fn try_for_each_recent(count: usize, mut f: impl FnMut(&Record)) -> bool {
+    let Some(buffer) = LOG_BUFFER.try_lock() else {
+        return false;
+    };
+    let available = buffer.iter().count();
+    for record in buffer.iter().skip(available.saturating_sub(count)) {
+        f(record);
+    }
+    true
+}
+
+/// Prints to the VGA buffer only, serial gets the plain text version in `log`
+fn print_to_console(record: &Record) {
+    let mut timestamp: FixedString<24> = FixedString::new();
+    let _ = write!(timestamp, "{} ", Timestamp(record.uptime_ms));
+    let parts = [
+        (Color::DarkGrey, Color::Black, timestamp.as_str()),
+        (Color::LightBlue, Color::Black, "["),
+        (Color::Black, record.level.badge_color(), record.file),
+        (Color::LightBlue, Color::Black, "] "),
+        (record.level.text_color(), Color::Black, record.message()),
+    ];
+    for (foreground, background, text) in parts {
+        send_command_to_writer(CommandToWriter::SetColor(foreground, background));
+        send_command_to_writer(CommandToWriter::Print(format_args!("{}", text)));
+    }
+    send_command_to_writer(CommandToWriter::SetColor(Color::White, Color::Black));
+    send_command_to_writer(CommandToWriter::Print(format_args!("\n")));
+}
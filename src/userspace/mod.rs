@@ -1,2 +1,4 @@
+pub mod logger;
 pub mod output;
+pub mod panic_screen;
 pub mod user_interface;
@@ -1,4 +1,31 @@
-use crate::low_level::vga_buffer::{send_command_to_writer, Color, CommandToWriter};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::low_level::{
+    serial,
+    vga_buffer::{send_command_to_writer, Color, CommandToWriter},
+};
+
+static SERIAL_MIRRORING: AtomicBool = AtomicBool::new(true);
+
+/// Turns mirroring of everything printed on screen to the COM1 serial port on or off
+pub fn set_serial_mirroring(enabled: bool) {
+    SERIAL_MIRRORING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn serial_mirroring() -> bool {
+    SERIAL_MIRRORING.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    send_command_to_writer(CommandToWriter::Print(args));
+    if serial_mirroring() {
+        serial::_print(args);
+    }
+}
 
 #[macro_export]
 macro_rules! print_with_colors {
@@ -8,12 +35,12 @@ macro_rules! print_with_colors {
                 $x.print_to_vga();
             )*
         }
-        $crate::low_level::vga_buffer::send_command_to_writer(popcorn::low_level::vga_buffer::CommandToWriter::SetColor(Color::White, Color::Black));
+        $crate::low_level::vga_buffer::send_command_to_writer($crate::low_level::vga_buffer::CommandToWriter::SetColor($crate::low_level::vga_buffer::Color::White, $crate::low_level::vga_buffer::Color::Black));
     }
 }
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => ($crate::low_level::vga_buffer::send_command_to_writer($crate::low_level::vga_buffer::CommandToWriter::Print(format_args!($($arg)*))));
+    ($($arg:tt)*) => ($crate::userspace::output::_print(format_args!($($arg)*)));
 }
 
 #[macro_export]
@@ -22,46 +49,54 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-/// log!("text") - this will get the file that called this, and say it as [file_name] <text>
+/// Logs at the given level, e.g. log_at!(Level::Debug, "{} frames", n).
+/// Every entry remembers the module and file that called this.
 #[macro_export]
-macro_rules! log {
-    ($($arg:tt)*) => (
-        $crate::print_with_colors!(
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::LightBlue, $crate::low_level::vga_buffer::Color::Black, "["),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::Black, $crate::low_level::vga_buffer::Color::LightGreen, core::file!()),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::LightBlue, $crate::low_level::vga_buffer::Color::Black, "] "),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::White, $crate::low_level::vga_buffer::Color::Black, $($arg)*)
-        );
-        $crate::println!();
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::userspace::logger::log(
+            $level,
+            core::module_path!(),
+            core::file!(),
+            format_args!($($arg)*),
+        )
     )
 }
 
+/// trace!("text") - very verbose output, hidden unless the module's level is lowered
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log_at!($crate::userspace::logger::Level::Trace, $($arg)*))
+}
+
+/// debug!("text") - output only useful while debugging
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log_at!($crate::userspace::logger::Level::Debug, $($arg)*))
+}
+
+/// info!("text") - this will get the file that called this, and say it as [file_name] <text>
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log_at!($crate::userspace::logger::Level::Info, $($arg)*))
+}
+
+/// log!("text") - same as info!
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => ($crate::info!($($arg)*))
+}
+
 /// warn!("text") - this will get the file that called this, and say it as [file_name] <text>
 #[macro_export]
 macro_rules! warn {
-    ($($arg:tt)*) => (
-        $crate::print_with_colors!(
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::LightBlue, $crate::low_level::vga_buffer::Color::Black, "["),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::Black, $crate::low_level::vga_buffer::Color::Yellow, core::file!()),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::LightBlue, $crate::low_level::vga_buffer::Color::Black, "] "),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::Yellow, $crate::low_level::vga_buffer::Color::Black, $($arg)*)
-        );
-        $crate::println!();
-    )
+    ($($arg:tt)*) => ($crate::log_at!($crate::userspace::logger::Level::Warn, $($arg)*))
 }
 
 /// error!("text") - this will get the file that called this, and say it as [file_name] <text>
 #[macro_export]
 macro_rules! error {
-    ($($arg:tt)*) => (
-        $crate::print_with_colors!(
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::LightBlue, $crate::low_level::vga_buffer::Color::Black, "["),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::Black, $crate::low_level::vga_buffer::Color::LightRed, core::file!()),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::LightBlue, $crate::low_level::vga_buffer::Color::Black, "] "),
-            $crate::userspace::output::MessageToVga::new($crate::low_level::vga_buffer::Color::LightRed, $crate::low_level::vga_buffer::Color::Black, $($arg)*)
-        );
-        $crate::println!();
-    )
+    ($($arg:tt)*) => ($crate::log_at!($crate::userspace::logger::Level::Error, $($arg)*))
 }
 
 pub struct MessageToVga<'a> {
@@ -83,3 +118,47 @@ impl<'a> MessageToVga<'a> {
         }
     }
 }
+
+/// A string with a fixed capacity that lives on the stack, so it can be formatted into
+/// before the heap exists or from interrupt handlers. Anything that doesn't fit is cut off.
+#[derive(Clone, Copy)]
+pub struct FixedString<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedString<N> {
+    pub const fn new() -> Self {
+        FixedString {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        //only whole characters are ever copied in, see write_str
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for FixedString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for FixedString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            let char_len = character.len_utf8();
+            if self.len + char_len > N {
+                break;
+            }
+            character.encode_utf8(&mut self.bytes[self.len..]);
+            self.len += char_len;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+//Full screen report shown when the kernel can't go on.
+//The text comes from the locale file, {N:spec} placeholders in it are filled in here:
+//0 = location, 1 = message, 2..=7 = registers (see RegisterDump), 8 = error code.
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::{
+        interrupts,
+        port::Port,
+        segmentation::{Segment, CS, SS},
+    },
+    registers::{control::Cr2, rflags},
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::{
+    hlt_loop,
+    low_level::{
+        power, serial,
+        vga_buffer::{
+            send_command_to_writer, Color, CommandToWriter, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER,
+        },
+    },
+    userspace::{
+        logger,
+        output::{serial_mirroring, FixedString},
+    },
+};
+
+const PANIC_TEXT: &str = include_str!("../../locale/en_panic.txt");
+/// At most this many of the latest kernel log lines are shown below the report
+pub const PANIC_LOG_LINES: usize = 3;
+const POWER_PROMPT: &str = "Press R to restart or S to shut down";
+const FOREGROUND: Color = Color::White;
+const BACKGROUND: Color = Color::Blue;
+
+const KEYBOARD_DATA: u16 = 0x60;
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const OUTPUT_BUFFER_FULL: u8 = 1 << 0;
+const MOUSE_DATA: u8 = 1 << 5;
+// Scancode set 1 make codes
+const SCANCODE_R: u8 = 0x13;
+const SCANCODE_S: u8 = 0x1F;
+
+/// The registers shown in the technical information of the panic screen
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterDump {
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+    /// CR2, the address of the last page fault
+    pub memory: u64,
+}
+
+impl RegisterDump {
+    /// Reads the registers of the code calling this
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (instruction_pointer, stack_pointer): (u64, u64);
+        unsafe {
+            asm!("lea {}, [rip]", out(reg) instruction_pointer, options(nomem, nostack));
+            asm!("mov {}, rsp", out(reg) stack_pointer, options(nomem, nostack));
+        }
+        RegisterDump {
+            instruction_pointer,
+            code_segment: CS::get_reg().0 as u64,
+            cpu_flags: rflags::read_raw(),
+            stack_pointer,
+            stack_segment: SS::get_reg().0 as u64,
+            memory: Cr2::read().as_u64(),
+        }
+    }
+
+    /// The registers at the time of an exception
+    pub fn from_stack_frame(stack_frame: &InterruptStackFrame) -> Self {
+        RegisterDump {
+            instruction_pointer: stack_frame.instruction_pointer.as_u64(),
+            code_segment: stack_frame.code_segment,
+            cpu_flags: stack_frame.cpu_flags,
+            stack_pointer: stack_frame.stack_pointer.as_u64(),
+            stack_segment: stack_frame.stack_segment,
+            memory: Cr2::read().as_u64(),
+        }
+    }
+}
+
+/// Registers and error code of the exception that is about to panic
+static FAULT: Mutex<Option<(RegisterDump, Option<u64>)>> = Mutex::new(None);
+
+/// Called by the exception handlers right before they panic, so the panic screen shows
+/// where the fault happened rather than where the handler panicked
+pub fn report_fault(registers: RegisterDump, code: Option<u64>) {
+    if let Some(mut fault) = FAULT.try_lock() {
+        *fault = Some((registers, code));
+    }
+}
+
+/// Shows the panic screen for a Rust panic and stops the machine
+pub fn show_panic(info: &PanicInfo) -> ! {
+    let captured = RegisterDump::capture();
+    interrupts::disable();
+    let (registers, code) = FAULT
+        .try_lock()
+        .and_then(|mut fault| fault.take())
+        .unwrap_or((captured, None));
+    match info.location() {
+        Some(location) => show(location, &info.message(), &registers, code),
+        None => show(&"unknown", &info.message(), &registers, code),
+    }
+}
+
+/// Clears the screen and renders the report, then waits with interrupts disabled
+/// until the user restarts or shuts down the machine
+pub fn show(
+    location: &dyn fmt::Display,
+    message: &dyn fmt::Display,
+    registers: &RegisterDump,
+    code: Option<u64>,
+) -> ! {
+    interrupts::disable();
+    //Whoever held the writer or the serial port is never going to release them now
+    if WRITER.is_locked() {
+        unsafe { WRITER.force_unlock() };
+    }
+    if serial::SERIAL1.is_locked() {
+        unsafe { serial::SERIAL1.force_unlock() };
+    }
+    send_command_to_writer(CommandToWriter::SetColor(FOREGROUND, BACKGROUND));
+    send_command_to_writer(CommandToWriter::ClearScreen(BACKGROUND));
+
+    let fields = [
+        Field::Text(location),
+        Field::Text(message),
+        Field::Number(registers.instruction_pointer),
+        Field::Number(registers.code_segment),
+        Field::Number(registers.cpu_flags),
+        Field::Number(registers.stack_pointer),
+        Field::Number(registers.stack_segment),
+        Field::Number(registers.memory),
+        Field::Code(code),
+    ];
+    let mut screen = Screen::new();
+    let _ = render_template(&mut screen, PANIC_TEXT, &fields);
+    let _ = screen.write_str("\n");
+
+    //the last row is kept for the power prompt
+    let log_lines = PANIC_LOG_LINES.min(screen.rows_left().saturating_sub(2));
+    if log_lines > 0 {
+        let _ = screen.write_str("LAST LOG:\n");
+        let shown = logger::try_for_each_recent(log_lines, |record| {
+            let mut line: FixedString<BUFFER_WIDTH> = FixedString::new();
+            let _ = write!(line, "{}", record);
+            let _ = writeln!(screen, "{}", line.as_str());
+        });
+        if !shown {
+            let _ = screen.write_str("(the log was in use)\n");
+        }
+    }
+    send_command_to_writer(CommandToWriter::WriteRow(BUFFER_HEIGHT - 1, POWER_PROMPT));
+    if serial_mirroring() {
+        serial::_print(format_args!("{}\n", POWER_PROMPT));
+    }
+    wait_for_power_key();
+}
+
+/// Polls the keyboard controller and the serial port, interrupts stay off
+fn wait_for_power_key() -> ! {
+    let mut status: Port<u8> = Port::new(KEYBOARD_CONTROLLER_STATUS);
+    let mut data: Port<u8> = Port::new(KEYBOARD_DATA);
+    loop {
+        let mut key = None;
+        unsafe {
+            let keyboard_status = status.read();
+            if keyboard_status & OUTPUT_BUFFER_FULL != 0 {
+                let scancode = data.read();
+                //bytes from the mouse are of no interest
+                if keyboard_status & MOUSE_DATA == 0 {
+                    key = match scancode {
+                        SCANCODE_R => Some(b'r'),
+                        SCANCODE_S => Some(b's'),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        if key.is_none() {
+            key = serial::try_receive_byte().map(|byte| byte.to_ascii_lowercase());
+        }
+        match key {
+            Some(b'r') => power::reboot(),
+            Some(b's') => {
+                power::shutdown();
+                hlt_loop();
+            }
+            _ => core::hint::spin_loop(),
+        }
+    }
+}
+
+enum Field<'a> {
+    Text(&'a dyn fmt::Display),
+    Number(u64),
+    Code(Option<u64>),
+}
+
+impl Field<'_> {
+    /// Supports the specs the locale files use: none, `?` and `[fill>]width x`
+    fn render(&self, out: &mut dyn Write, spec: &str) -> fmt::Result {
+        match self {
+            Field::Text(text) => write!(out, "{}", text),
+            Field::Code(code) => write!(out, "{:?}", code),
+            Field::Number(value) => match spec.strip_suffix('x') {
+                Some(padding) => {
+                    let (fill, width) = match padding.split_once('>') {
+                        Some((fill, width)) => (fill.chars().next().unwrap_or(' '), width),
+                        None => (' ', padding),
+                    };
+                    let mut digits: FixedString<16> = FixedString::new();
+                    write!(digits, "{:x}", value)?;
+                    let width = width.parse::<usize>().unwrap_or(0);
+                    for _ in digits.as_str().len()..width {
+                        out.write_char(fill)?;
+                    }
+                    out.write_str(digits.as_str())
+                }
+                None => write!(out, "{}", value),
+            },
+        }
+    }
+}
+
+/// Copies the template to `out`, replacing every `{index:spec}` with the matching field
+fn render_template(out: &mut dyn Write, template: &str, fields: &[Field]) -> fmt::Result {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.write_str(&rest[..start])?;
+        let Some(end) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let placeholder = &rest[start + 1..start + end];
+        let (index, spec) = placeholder.split_once(':').unwrap_or((placeholder, ""));
+        match index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| fields.get(index))
+        {
+            Some(field) => field.render(out, spec)?,
+            None => out.write_str(&rest[start..=start + end])?,
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.write_str(rest)
+}
+
+/// Writes text row by row from the top of the screen, wrapping long lines.
+/// Also mirrors everything to serial, so the report ends up in captured logs.
+struct Screen {
+    row: usize,
+    line: FixedString<BUFFER_WIDTH>,
+    line_len: usize,
+    /// A line exactly as wide as the screen shouldn't leave an empty row behind
+    just_wrapped: bool,
+}
+
+impl Screen {
+    fn new() -> Self {
+        Screen {
+            row: 0,
+            line: FixedString::new(),
+            line_len: 0,
+            just_wrapped: false,
+        }
+    }
+    fn rows_left(&self) -> usize {
+        BUFFER_HEIGHT.saturating_sub(self.row)
+    }
+    fn flush_line(&mut self) {
+        send_command_to_writer(CommandToWriter::WriteRow(self.row, self.line.as_str()));
+        self.row += 1;
+        self.line.clear();
+        self.line_len = 0;
+    }
+}
+
+impl Write for Screen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if serial_mirroring() {
+            serial::_print(format_args!("{}", s));
+        }
+        for character in s.chars() {
+            if self.row >= BUFFER_HEIGHT {
+                break;
+            }
+            let just_wrapped = core::mem::take(&mut self.just_wrapped);
+            match character {
+                '\n' if just_wrapped => {}
+                '\n' => self.flush_line(),
+                //the VGA buffer only knows code page 437, keep it to printable ASCII
+                character if character == ' ' || character.is_ascii_graphic() => {
+                    self.line.write_char(character)?;
+                    self.line_len += 1;
+                }
+                _ => {
+                    self.line.write_char('?')?;
+                    self.line_len += 1;
+                }
+            }
+            if self.line_len == BUFFER_WIDTH {
+                self.flush_line();
+                self.just_wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::low_level::acpi;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn tables_are_found_and_valid() {
+    assert!(!acpi::tables().is_empty());
+    assert!(acpi::tables().iter().all(acpi::Table::checksum_ok));
+}
+
+#[test_case]
+fn fadt_has_power_management_ports() {
+    let fadt = acpi::fadt().expect("no FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert!(acpi::dsdt().is_some());
+}
+
+#[test_case]
+fn hpet_is_described() {
+    // qemu provides an HPET by default
+    let hpet = acpi::hpet().expect("no HPET");
+    assert_eq!(hpet.base_address.address_space, acpi::AddressSpace::Memory);
+    assert!(hpet.comparators >= 3);
+}
+
+#[test_case]
+fn soft_off_sleep_type_is_found() {
+    assert!(popcorn::low_level::power::s5_sleep_types().is_some());
+}
@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Compare the allocators by running this with and without `--no-default-features`
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{arch::x86_64::_rdtsc, panic::PanicInfo};
+use popcorn::serial_print;
+
+entry_point!(main);
+
+const ROUNDS: u64 = 1000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+/// Runs `f` ROUNDS times and prints the average number of cycles it took
+fn measure(name: &str, mut f: impl FnMut()) {
+    let start = unsafe { _rdtsc() };
+    for _ in 0..ROUNDS {
+        f();
+    }
+    let cycles = unsafe { _rdtsc() } - start;
+    serial_print!("{}: {} cycles ", name, cycles / ROUNDS);
+}
+
+#[test_case]
+fn small_boxes() {
+    measure("box u64", || drop(Box::new(0u64)));
+    measure("box [u8; 200]", || drop(Box::new([0u8; 200])));
+}
+
+#[test_case]
+fn fragmented_heap() {
+    // keep every other allocation alive, so the linked list has many holes to search
+    let mut kept = Vec::new();
+    measure("interleaved", || {
+        let keep = Box::new([0u8; 48]);
+        drop(Box::new([0u8; 48]));
+        kept.push(keep);
+    });
+    assert_eq!(kept.len(), ROUNDS as usize);
+}
+
+#[test_case]
+fn large_allocations() {
+    measure("vec 16 KiB", || drop(Vec::<u8>::with_capacity(16 * 1024)));
+}
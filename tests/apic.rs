@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::low_level::{acpi, apic, timer};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_describes_the_interrupt_controllers() {
+    let madt = acpi::madt().expect("no MADT");
+    assert!(madt.processors.iter().any(|processor| processor.enabled));
+    assert!(!madt.io_apics.is_empty());
+}
+
+#[test_case]
+fn apic_is_used_when_present() {
+    // qemu always emulates an APIC
+    assert!(apic::is_supported());
+    assert!(apic::is_enabled());
+}
+
+#[test_case]
+fn apic_timer_matches_the_configured_frequency() {
+    let start = timer::ticks();
+    timer::busy_wait_us(100_000);
+    let ticks = timer::ticks() - start;
+    let expected = timer::frequency() as u64 / 10;
+    assert!(ticks.abs_diff(expected) <= expected / 5);
+}
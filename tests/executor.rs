@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    panic::PanicInfo,
+    task::{Poll, Waker},
+};
+use popcorn::{
+    low_level::{interrupts::TIMER_IRQ, irq, timer},
+    task::{self, Executor},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn tasks_run_to_completion() {
+    let done = Rc::new(RefCell::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        let done = done.clone();
+        executor.spawn(async move { *done.borrow_mut() += 1 });
+    }
+    assert_eq!(executor.task_count(), 3);
+    executor.run_until_done();
+    assert_eq!(*done.borrow(), 3);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn yielding_lets_other_tasks_run() {
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for name in ['a', 'b'] {
+        let order = order.clone();
+        executor.spawn(async move {
+            for step in 0..2 {
+                order.borrow_mut().push((name, step));
+                task::yield_now().await;
+            }
+        });
+    }
+    executor.run_until_done();
+    assert_eq!(
+        *order.borrow(),
+        vec![('a', 0), ('b', 0), ('a', 1), ('b', 1)]
+    );
+}
+
+#[test_case]
+fn tasks_can_spawn_tasks() {
+    let done = Rc::new(RefCell::new(false));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let inner_done = done.clone();
+    executor.spawn(async move {
+        spawner.spawn(async move { *inner_done.borrow_mut() = true });
+    });
+    executor.run_until_done();
+    assert!(*done.borrow());
+}
+
+static TICK_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+fn wake_on_tick(_irq: u8) {
+    if let Some(waker) = TICK_WAKER.lock().take() {
+        waker.wake();
+    }
+}
+
+/// Completes after the next timer interrupt
+async fn next_tick() {
+    let start = timer::ticks();
+    poll_fn(|context| {
+        if timer::ticks() > start {
+            return Poll::Ready(());
+        }
+        interrupts::without_interrupts(|| {
+            *TICK_WAKER.lock() = Some(context.waker().clone());
+        });
+        Poll::Pending
+    })
+    .await
+}
+
+#[test_case]
+fn interrupts_wake_tasks() {
+    irq::register_irq(TIMER_IRQ, wake_on_tick).unwrap();
+    let ticks = Rc::new(RefCell::new(0));
+    let mut executor = Executor::new();
+    let task_ticks = ticks.clone();
+    executor.spawn(async move {
+        for _ in 0..5 {
+            next_tick().await;
+            *task_ticks.borrow_mut() += 1;
+        }
+    });
+    let start = timer::ticks();
+    executor.run_until_done();
+    assert_eq!(*ticks.borrow(), 5);
+    assert!(timer::ticks() >= start + 5);
+    assert!(irq::unregister_irq(TIMER_IRQ, wake_on_tick));
+}
+
+#[test_case]
+fn repeated_wakes_queue_a_task_once() {
+    let polls = Rc::new(RefCell::new(0));
+    let mut executor = Executor::new();
+    let task_polls = polls.clone();
+    executor.spawn(poll_fn(move |context| {
+        *task_polls.borrow_mut() += 1;
+        if *task_polls.borrow() == 2 {
+            return Poll::Ready(());
+        }
+        // more wakes than the ready queue has room for
+        for _ in 0..1000 {
+            context.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }));
+    executor.run_until_done();
+    assert_eq!(*polls.borrow(), 2);
+}
+
+#[test_case]
+fn tasks_that_dont_fit_in_the_ready_queue_still_run() {
+    let done = Rc::new(RefCell::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..1000 {
+        let done = done.clone();
+        executor.spawn(async move {
+            task::yield_now().await;
+            *done.borrow_mut() += 1;
+        });
+    }
+    executor.run_until_done();
+    assert_eq!(*done.borrow(), 1000);
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::low_level::memory::PopFrameAllocator;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+// The heap is left uninitialized, so this allocator owns every usable frame
+static ALLOCATOR: Mutex<Option<PopFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let allocator = unsafe { PopFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *ALLOCATOR.lock() = Some(allocator);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+fn with_allocator(f: impl FnOnce(&mut PopFrameAllocator)) {
+    f(ALLOCATOR.lock().as_mut().unwrap())
+}
+
+#[test_case]
+fn allocated_frames_are_distinct() {
+    with_allocator(|allocator| {
+        let first = allocator.allocate_frame().unwrap();
+        let second = allocator.allocate_frame().unwrap();
+        assert_ne!(first, second);
+        unsafe {
+            allocator.deallocate_frame(first);
+            allocator.deallocate_frame(second);
+        }
+    });
+}
+
+#[test_case]
+fn deallocated_frame_is_reused() {
+    with_allocator(|allocator| {
+        let frame = allocator.allocate_frame().unwrap();
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+        unsafe { allocator.deallocate_frame(frame) };
+    });
+}
+
+#[test_case]
+fn counts_follow_allocations() {
+    with_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let used = allocator.used_frames();
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.free_frames(), free - 1);
+        assert_eq!(allocator.used_frames(), used + 1);
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.free_frames(), free);
+    });
+}
+
+#[test_case]
+fn contiguous_allocation_is_aligned() {
+    with_allocator(|allocator| {
+        let range = allocator.allocate_contiguous(16, 16).unwrap();
+        assert_eq!(range.count(), 16);
+        assert_eq!(range.start.start_address().as_u64() % (16 * 4096), 0);
+        unsafe { allocator.deallocate_contiguous(range) };
+    });
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{fmt::Write, panic::PanicInfo};
+use popcorn::{
+    exit_qemu, low_level::memory::PopFrameAllocator, serial_print, serial_println,
+    userspace::output::FixedString, QemuExitCode,
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("frame_double_free::is_caught...\t");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut allocator = unsafe { PopFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe {
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+    }
+
+    serial_println!("[failed]\nThe second free went through");
+    exit_qemu(QemuExitCode::Failed);
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message: FixedString<256> = FixedString::new();
+    let _ = write!(message, "{}", info.message());
+    let message = message.as_str();
+    if message.contains("is already free") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\nUnexpected panic: {}", message);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    popcorn::hlt_loop();
+}
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{arch::asm, fmt::Write, panic::PanicInfo};
+use popcorn::{
+    exit_qemu, serial_print, serial_println, userspace::output::FixedString, QemuExitCode,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("general_protection_fault::reports_the_selector...\t");
+    popcorn::init(boot_info);
+
+    // there is no GDT entry 582, so loading its selector raises #GP with it as the error code
+    unsafe { asm!("mov ds, {0:x}", in(reg) 0x1230u16) };
+
+    serial_println!("[failed]\nExecution continued after the fault");
+    exit_qemu(QemuExitCode::Failed);
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message: FixedString<256> = FixedString::new();
+    let _ = write!(message, "{}", info.message());
+    let message = message.as_str();
+    if message.contains("GENERAL PROTECTION FAULT") && message.contains("Gdt entry 582") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\nUnexpected panic: {}", message);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    popcorn::hlt_loop();
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::low_level::{
+    allocator::{self, HEAP_SIZE},
+    memory,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let initial_size = allocator::heap_size();
+    let vec: Vec<u8> = alloc::vec![0xAB; 2 * HEAP_SIZE];
+    assert!(allocator::heap_size() > initial_size);
+    assert!(vec.iter().all(|&byte| byte == 0xAB));
+}
+
+#[test_case]
+fn growth_stops_at_the_limit() {
+    let limit = allocator::heap_limit();
+    // more than the heap can have free, so it has to grow for it
+    let size = allocator::heap_size() + 1;
+    let mut vec: Vec<u8> = Vec::new();
+    allocator::set_heap_limit(allocator::heap_size());
+    assert!(vec.try_reserve_exact(size).is_err());
+    allocator::set_heap_limit(limit);
+    assert!(vec.try_reserve_exact(size).is_ok());
+}
+
+#[test_case]
+fn stats_count_allocations() {
+    let before = allocator::heap_stats();
+    let value = Box::new([0u8; 256]);
+    let during = allocator::heap_stats();
+    drop(value);
+    let after = allocator::heap_stats();
+
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.used, before.used + 256);
+    assert!(during.peak_used >= during.used);
+    assert_eq!(after.deallocations, before.deallocations + 1);
+    assert_eq!(after.used, before.used);
+}
+
+#[test_case]
+fn memory_stats_report_physical_memory() {
+    let stats = memory::stats();
+    assert!(stats.usable_physical > 0);
+    assert!(stats.usable_physical <= stats.total_physical);
+    assert!(stats.frames_used > 0);
+}
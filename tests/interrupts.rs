@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn interrupts_enabled_after_init() {
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn breakpoint_exception_returns() {
+    // the breakpoint handler only reports the exception, so execution must continue
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn timer_interrupt_wakes_hlt() {
+    // hlt only returns once an interrupt arrives, so this hangs if the PIT is not delivered
+    x86_64::instructions::hlt();
+}
@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use popcorn::low_level::{
+    apic,
+    interrupts::{self, TIMER_IRQ},
+    irq::{self, IrqError},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+// IRQ 5 is free on qemu, the tests raise it with `int` on its vector (32 + 5).
+// It isn't in service at the controller then, so dispatching it sends no EOI.
+const TEST_IRQ: u8 = 5;
+static FIRST_CALLS: AtomicUsize = AtomicUsize::new(0);
+static SECOND_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn first(_irq: u8) {
+    FIRST_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn second(_irq: u8) {
+    SECOND_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn raise_test_irq() {
+    unsafe { asm!("int 0x25") };
+}
+
+#[test_case]
+fn timer_irq_is_counted() {
+    let start = irq::count(TIMER_IRQ);
+    x86_64::instructions::hlt();
+    assert!(irq::count(TIMER_IRQ) > start);
+}
+
+#[test_case]
+fn registered_handlers_share_an_irq() {
+    irq::register_irq(TEST_IRQ, first).unwrap();
+    irq::register_irq(TEST_IRQ, second).unwrap();
+    let count = irq::count(TEST_IRQ);
+    raise_test_irq();
+    assert_eq!(FIRST_CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(SECOND_CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(irq::count(TEST_IRQ), count + 1);
+}
+
+#[test_case]
+fn unregistered_handler_is_not_called() {
+    assert!(irq::unregister_irq(TEST_IRQ, first));
+    assert!(!irq::unregister_irq(TEST_IRQ, first));
+    raise_test_irq();
+    assert_eq!(FIRST_CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(SECOND_CALLS.load(Ordering::Relaxed), 2);
+    assert!(irq::unregister_irq(TEST_IRQ, second));
+    assert!(!irq::has_handlers(TEST_IRQ));
+}
+
+#[test_case]
+fn idle_irq_7_is_spurious() {
+    // nothing is being handled, an IRQ 7 from the PICs now could only be spurious
+    assert_eq!(interrupts::is_spurious(7), !apic::is_enabled());
+    assert!(!interrupts::is_spurious(TEST_IRQ));
+}
+
+#[test_case]
+fn registration_errors() {
+    assert_eq!(irq::register_irq(16, first), Err(IrqError::InvalidIrq));
+    irq::register_irq(TEST_IRQ, first).unwrap();
+    assert_eq!(
+        irq::register_irq(TEST_IRQ, first),
+        Err(IrqError::AlreadyRegistered)
+    );
+    irq::unregister_irq(TEST_IRQ, first);
+}
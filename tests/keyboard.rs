@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{arch::asm, panic::PanicInfo};
+use futures_util::{
+    stream::StreamExt,
+    task::{noop_waker, Context, Poll},
+};
+use popcorn::task::keyboard::{self, ScancodeStream, SCANCODE_QUEUE_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+// runs the keyboard handler on IRQ 1's vector (32 + 1), it queues whatever is in the data port
+fn raise_keyboard_irq() {
+    unsafe { asm!("int 0x21") };
+}
+
+#[test_case]
+fn scancodes_are_queued_and_dropped_when_full() {
+    let mut scancodes = ScancodeStream::new();
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    assert_eq!(scancodes.poll_next_unpin(&mut context), Poll::Pending);
+
+    raise_keyboard_irq();
+    assert!(matches!(
+        scancodes.poll_next_unpin(&mut context),
+        Poll::Ready(Some(_))
+    ));
+    assert_eq!(scancodes.poll_next_unpin(&mut context), Poll::Pending);
+
+    let dropped = keyboard::dropped_scancodes();
+    for _ in 0..SCANCODE_QUEUE_SIZE + 3 {
+        raise_keyboard_irq();
+    }
+    assert_eq!(keyboard::dropped_scancodes(), dropped + 3);
+    for _ in 0..SCANCODE_QUEUE_SIZE {
+        assert!(matches!(
+            scancodes.poll_next_unpin(&mut context),
+            Poll::Ready(Some(_))
+        ));
+    }
+    assert_eq!(scancodes.poll_next_unpin(&mut context), Poll::Pending);
+}
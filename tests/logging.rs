@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{fmt::Write, panic::PanicInfo};
+use popcorn::{
+    debug, log,
+    userspace::{
+        logger::{self, Level, Record},
+        output::FixedString,
+    },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+fn last_record() -> Option<Record> {
+    let mut last = None;
+    logger::for_each_recent(1, |record| last = Some(*record));
+    last
+}
+
+#[test_case]
+fn formats_arguments() {
+    log!("{} frames", 42);
+    let record = last_record().expect("record was not stored");
+    assert_eq!(record.message(), "42 frames");
+    assert_eq!(record.level, Level::Info);
+    assert_eq!(record.module, module_path!());
+}
+
+#[test_case]
+fn drops_messages_below_max_level() {
+    log!("before debug");
+    debug!("hidden by default");
+    assert_eq!(last_record().unwrap().message(), "before debug");
+}
+
+#[test_case]
+fn module_filter_overrides_max_level() {
+    assert!(logger::set_module_level(module_path!(), Level::Trace));
+    debug!("visible now");
+    logger::clear_module_levels();
+    assert_eq!(last_record().unwrap().message(), "visible now");
+}
+
+#[test_case]
+fn ring_buffer_keeps_latest_records() {
+    for i in 0..logger::LOG_CAPACITY + 10 {
+        log!("message {}", i);
+    }
+    let mut count = 0;
+    logger::for_each_recent(logger::LOG_CAPACITY, |_| count += 1);
+    assert_eq!(count, logger::LOG_CAPACITY);
+    let mut expected: FixedString<32> = FixedString::new();
+    write!(expected, "message {}", logger::LOG_CAPACITY + 9).unwrap();
+    assert_eq!(last_record().unwrap().message(), expected.as_str());
+}
+
+#[test_case]
+fn try_for_each_recent_matches_for_each_recent() {
+    log!("first");
+    log!("second");
+    let mut messages: [FixedString<32>; 2] = [FixedString::new(), FixedString::new()];
+    let mut index = 0;
+    assert!(logger::try_for_each_recent(2, |record| {
+        write!(messages[index], "{}", record.message()).unwrap();
+        index += 1;
+    }));
+    assert_eq!(messages[0].as_str(), "first");
+    assert_eq!(messages[1].as_str(), "second");
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::low_level::{
+    clock,
+    rtc::{self, DateTime},
+    timer,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn rtc_reading_is_a_valid_date() {
+    let now = rtc::read();
+    assert!(now.year >= 2000);
+    assert!((1..=12).contains(&now.month));
+    assert!((1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn unix_time_round_trips() {
+    let time = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 5,
+    };
+    assert_eq!(time.to_unix(), 1_709_213_825);
+    assert_eq!(DateTime::from_unix(time.to_unix()), time);
+    assert_eq!(DateTime::from_unix(0).year, 1970);
+}
+
+#[test_case]
+fn wall_clock_follows_the_timer() {
+    let start = clock::unix_time_ms().expect("clock was not initialized");
+    timer::sleep_ms(20);
+    assert!(clock::unix_time_ms().unwrap() - start >= 20);
+    // the wall clock and the RTC shouldn't drift apart in the short time the tests run
+    let drift = clock::unix_time().unwrap().abs_diff(rtc::read().to_unix());
+    assert!(drift <= 2);
+}
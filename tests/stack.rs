@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, ptr};
+use popcorn::low_level::{gdt, memory, stack, vmm};
+use x86_64::structures::paging::Translate;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+fn is_mapped(address: x86_64::VirtAddr) -> bool {
+    memory::with_mapper_and_allocator(|mapper, _| mapper.translate_addr(address).is_some()).unwrap()
+}
+
+#[test_case]
+fn stacks_have_an_unmapped_guard_page() {
+    let stack = stack::allocate("test stack", 8192).expect("no stack");
+    assert_eq!(stack.size(), 8192);
+    unsafe {
+        ptr::write_volatile(stack.bottom.as_mut_ptr::<u8>(), 1);
+        ptr::write_volatile((stack.top - 1u64).as_mut_ptr::<u8>(), 1);
+    }
+    assert!(!is_mapped(stack.guard_page().start_address()));
+}
+
+#[test_case]
+fn exception_stacks_are_guarded() {
+    for index in [
+        gdt::DOUBLE_FAULT_IST_INDEX,
+        gdt::NMI_IST_INDEX,
+        gdt::MACHINE_CHECK_IST_INDEX,
+    ] {
+        let top = gdt::interrupt_stack(index);
+        let area = vmm::area_at(top - 1u64).expect("stack outside of any area");
+        assert_eq!(area.name, "kernel stacks");
+    }
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{arch::asm, fmt::Write, hint::black_box, panic::PanicInfo};
+use popcorn::{
+    exit_qemu, low_level::stack, serial_print, serial_println, userspace::output::FixedString,
+    QemuExitCode,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::reports_the_stack...\t");
+    popcorn::init(boot_info);
+
+    let stack = stack::allocate("overflow test stack", 4096 * 4).expect("no stack");
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {entry}",
+            top = in(reg) stack.top.as_u64(),
+            entry = sym overflow_entry,
+            options(noreturn)
+        )
+    };
+}
+
+extern "C" fn overflow_entry() -> ! {
+    recurse(0);
+    serial_println!("[failed]\nExecution continued after the overflow");
+    exit_qemu(QemuExitCode::Failed);
+    popcorn::hlt_loop();
+}
+
+#[allow(unconditional_recursion)]
+fn recurse(depth: u64) -> u64 {
+    // the frame has to stay on the stack, so this can't become a loop
+    let frame = black_box([depth; 16]);
+    recurse(depth + 1) + frame[0]
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message: FixedString<256> = FixedString::new();
+    let _ = write!(message, "{}", info.message());
+    let message = message.as_str();
+    if message.contains("KERNEL STACK OVERFLOW") && message.contains("overflow test stack") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\nUnexpected panic: {}", message);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    popcorn::hlt_loop();
+}
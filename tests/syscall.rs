@@ -0,0 +1,239 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::{
+    low_level::vmm,
+    task::{
+        syscall::{self, SyscallError},
+        user::{self, ExitStatus},
+    },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+/// Assembles the few instructions the test programs need
+struct Program {
+    code: Vec<u8>,
+    /// Where the displacement of `lea_rdi_data` goes
+    data_patch: Option<usize>,
+}
+
+impl Program {
+    fn new() -> Self {
+        Program {
+            code: Vec::new(),
+            data_patch: None,
+        }
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.code.extend_from_slice(bytes);
+        self
+    }
+
+    fn imm32(self, opcode: u8, value: u32) -> Self {
+        self.bytes(&[opcode]).bytes(&value.to_le_bytes())
+    }
+
+    fn mov_eax(self, value: u64) -> Self {
+        self.imm32(0xB8, value as u32)
+    }
+
+    fn mov_edi(self, value: u32) -> Self {
+        self.imm32(0xBF, value)
+    }
+
+    fn mov_esi(self, value: u32) -> Self {
+        self.imm32(0xBE, value)
+    }
+
+    fn movabs_rdi(self, value: u64) -> Self {
+        self.bytes(&[0x48, 0xBF]).bytes(&value.to_le_bytes())
+    }
+
+    /// lea rdi, [rip + data], the data comes after the code
+    fn lea_rdi_data(mut self) -> Self {
+        self.code.extend_from_slice(&[0x48, 0x8D, 0x3D]);
+        self.data_patch = Some(self.code.len());
+        self.bytes(&[0; 4])
+    }
+
+    fn syscall(self, number: u64) -> Self {
+        self.mov_eax(number).bytes(&[0x0F, 0x05])
+    }
+
+    /// Exits with the result of the last call as the code
+    fn exit_with_result(self) -> Vec<u8> {
+        self.bytes(&[0x48, 0x89, 0xC7]) // mov rdi, rax
+            .syscall(syscall::EXIT)
+            .finish(&[])
+    }
+
+    fn finish(mut self, data: &[u8]) -> Vec<u8> {
+        if let Some(patch) = self.data_patch {
+            let displacement = (self.code.len() - (patch + 4)) as u32;
+            self.code[patch..patch + 4].copy_from_slice(&displacement.to_le_bytes());
+        }
+        self.code.extend_from_slice(data);
+        self.code
+    }
+}
+
+fn run(name: &'static str, code: &[u8]) -> ExitStatus {
+    user::spawn(name, code).unwrap().wait()
+}
+
+#[test_case]
+fn exit_returns_the_code() {
+    let code = Program::new()
+        .mov_edi(42)
+        .syscall(syscall::EXIT)
+        .finish(&[]);
+    assert_eq!(run("exit", &code), ExitStatus::Exited(42));
+}
+
+#[test_case]
+fn write_prints_text() {
+    let text = b"hello from ring 3\n";
+    let code = Program::new()
+        .lea_rdi_data()
+        .mov_esi(text.len() as u32)
+        .syscall(syscall::WRITE)
+        .bytes(&[0x48, 0x89, 0xC7]) // mov rdi, rax
+        .syscall(syscall::EXIT)
+        .finish(text);
+    assert_eq!(run("write", &code), ExitStatus::Exited(text.len() as u64));
+}
+
+#[test_case]
+fn kernel_pointers_are_rejected() {
+    let secret = Box::new(*b"secret");
+    let code = Program::new()
+        .movabs_rdi(secret.as_ptr() as u64)
+        .mov_esi(secret.len() as u32)
+        .syscall(syscall::WRITE)
+        .exit_with_result();
+    assert_eq!(
+        run("bad pointer", &code),
+        ExitStatus::Exited(SyscallError::BadAddress.to_result())
+    );
+}
+
+#[test_case]
+fn writes_past_the_program_are_rejected() {
+    // starts inside the code, but runs past the end of its area
+    let code = Program::new()
+        .lea_rdi_data()
+        .mov_esi(syscall::MAX_WRITE_LEN as u32)
+        .syscall(syscall::WRITE)
+        .exit_with_result();
+    assert_eq!(
+        run("too long", &code),
+        ExitStatus::Exited(SyscallError::BadAddress.to_result())
+    );
+}
+
+#[test_case]
+fn unknown_calls_fail() {
+    let code = Program::new().syscall(99).exit_with_result();
+    let ExitStatus::Exited(result) = run("unknown", &code) else {
+        panic!("the program was killed");
+    };
+    assert_eq!(
+        SyscallError::from_result(result),
+        Err(SyscallError::UnknownCall)
+    );
+}
+
+#[test_case]
+fn sleep_takes_time() {
+    let code = Program::new()
+        .syscall(syscall::GET_TIME)
+        .bytes(&[0x48, 0x89, 0xC3]) // mov rbx, rax
+        .mov_edi(20)
+        .syscall(syscall::SLEEP)
+        .syscall(syscall::GET_TIME)
+        .bytes(&[0x48, 0x29, 0xD8]) // sub rax, rbx
+        .exit_with_result();
+    let ExitStatus::Exited(elapsed) = run("sleep", &code) else {
+        panic!("the program was killed");
+    };
+    assert!(elapsed >= 20, "slept for {} ms", elapsed);
+}
+
+#[test_case]
+fn read_key_without_input() {
+    let code = Program::new().syscall(syscall::READ_KEY).exit_with_result();
+    assert_eq!(run("read key", &code), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn mapped_memory_is_usable() {
+    let code = Program::new()
+        .mov_edi(5000)
+        .syscall(syscall::MMAP)
+        .bytes(&[0xC6, 0x80, 0x00, 0x10, 0x00, 0x00, 0x05]) // mov byte [rax + 4096], 5
+        .bytes(&[0x0F, 0xB6, 0xB8, 0x00, 0x10, 0x00, 0x00]) // movzx edi, byte [rax + 4096]
+        .syscall(syscall::EXIT)
+        .finish(&[]);
+    assert_eq!(run("mmap", &code), ExitStatus::Exited(5));
+}
+
+#[test_case]
+fn calls_can_read_untouched_memory() {
+    // the kernel is the first to access the page, it gets backed before the call reads it
+    let faults = vmm::demand_faults();
+    let code = Program::new()
+        .mov_edi(4096)
+        .syscall(syscall::MMAP)
+        .bytes(&[0x48, 0x89, 0xC7]) // mov rdi, rax
+        .mov_esi(1)
+        .syscall(syscall::WRITE)
+        .exit_with_result();
+    assert_eq!(run("untouched", &code), ExitStatus::Exited(1));
+    assert!(vmm::demand_faults() > faults);
+}
+
+#[test_case]
+fn mappings_are_limited() {
+    let code = Program::new()
+        .mov_edi((user::MAX_MAPPED_BYTES + 4096) as u32)
+        .syscall(syscall::MMAP)
+        .exit_with_result();
+    assert_eq!(
+        run("mmap too much", &code),
+        ExitStatus::Exited(SyscallError::OutOfMemory.to_result())
+    );
+}
+
+#[test_case]
+fn unmapped_memory_is_gone() {
+    let code = Program::new()
+        .mov_edi(4096)
+        .syscall(syscall::MMAP)
+        .bytes(&[0x48, 0x89, 0xC3]) // mov rbx, rax
+        .bytes(&[0x48, 0x89, 0xC7]) // mov rdi, rax
+        .syscall(syscall::MUNMAP)
+        .bytes(&[0xC6, 0x03, 0x01]) // mov byte [rbx], 1
+        .finish(&[]);
+    assert_eq!(run("munmap", &code), ExitStatus::Killed("PAGE FAULT"));
+}
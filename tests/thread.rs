@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    hint::spin_loop,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use popcorn::{
+    low_level::{allocator, stack, timer},
+    task::thread,
+};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_the_result() {
+    let handle = thread::spawn("adder", || (1..=10u64).sum::<u64>()).unwrap();
+    assert_eq!(handle.join(), Some(55));
+}
+
+#[test_case]
+fn busy_threads_get_preempted() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn("setter", || RAN.store(true, Ordering::SeqCst)).unwrap();
+    // never yields, only the timer can let the other thread run
+    while !RAN.load(Ordering::SeqCst) {
+        spin_loop();
+    }
+    handle.join();
+}
+
+#[test_case]
+fn yielding_alternates_threads() {
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = ['a', 'b']
+        .into_iter()
+        .map(|name| {
+            let order = order.clone();
+            thread::spawn("yielder", move || {
+                for step in 0..2 {
+                    order.lock().push((name, step));
+                    thread::yield_now();
+                }
+            })
+            .unwrap()
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    let order = order.lock();
+    assert_eq!(order.len(), 4);
+    // every thread yields after each step, so neither runs twice in a row
+    assert!(order.windows(2).all(|pair| pair[0].0 != pair[1].0));
+    assert!(order.contains(&('a', 1)) && order.contains(&('b', 1)));
+}
+
+#[test_case]
+fn sleep_blocks_for_the_duration() {
+    let start = timer::uptime_ms();
+    let handle = thread::spawn("sleeper", || {
+        thread::sleep(50);
+        timer::uptime_ms()
+    })
+    .unwrap();
+    assert!(handle.join().unwrap() >= start + 50);
+
+    let start = timer::uptime_ms();
+    thread::sleep(20);
+    assert!(timer::uptime_ms() >= start + 20);
+}
+
+#[test_case]
+fn finished_threads_are_cleaned_up() {
+    // more threads than there are stacks, so they have to be freed again
+    for round in 0..stack::MAX_STACKS as u64 + 8 {
+        let handle = thread::spawn("short", move || round).unwrap();
+        assert_eq!(handle.join(), Some(round));
+    }
+    let handles: Vec<_> = (0..4)
+        .map(|_| thread::spawn("detached", || thread::sleep(1)).unwrap())
+        .collect();
+    drop(handles);
+    thread::sleep(20);
+    thread::spawn("reaper", || {}).unwrap().join();
+    assert_eq!(thread::thread_count(), 1);
+    assert_eq!(thread::current_name(), "kernel main");
+}
+
+#[test_case]
+fn exited_threads_free_what_they_were_spawned_with() {
+    let used = allocator::heap_stats().used;
+    let handles: Vec<_> = (0..4)
+        .map(|_| thread::spawn("exiter", || -> u64 { thread::exit() }).unwrap())
+        .collect();
+    drop(handles);
+    thread::sleep(20);
+    thread::spawn("reaper", || {}).unwrap().join();
+    assert_eq!(allocator::heap_stats().used, used);
+}
+
+#[test_case]
+fn exited_threads_leave_no_result() {
+    let handle = thread::spawn("exiter", || -> u64 { thread::exit() }).unwrap();
+    assert_eq!(handle.join(), None);
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::low_level::timer;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+#[test_case]
+fn runs_at_configured_frequency() {
+    // the divisor rounds, so the exact frequency is a little off
+    assert!(timer::frequency().abs_diff(timer::DEFAULT_TIMER_FREQUENCY) <= 1);
+}
+
+#[test_case]
+fn ticks_increase() {
+    let start = timer::ticks();
+    x86_64::instructions::hlt();
+    x86_64::instructions::hlt();
+    assert!(timer::ticks() > start);
+}
+
+#[test_case]
+fn sleep_waits_at_least_the_given_time() {
+    let start = timer::uptime_ms();
+    timer::sleep_ms(50);
+    assert!(timer::uptime_ms() - start >= 50);
+}
+
+#[test_case]
+fn busy_wait_works_without_interrupts() {
+    let start = timer::ticks();
+    x86_64::instructions::interrupts::without_interrupts(|| timer::busy_wait_us(5_000));
+    // 5 ms pass, but with interrupts off at most the one pending tick gets counted afterwards
+    // (plus one that may have come in before they were disabled)
+    assert!(timer::ticks() <= start + 2);
+}
+
+#[test_case]
+fn uptime_survives_frequency_changes() {
+    // the PIT count behind busy_wait_us is the independent clock here
+    const SPAN_MS: u64 = 50;
+    for frequency in [100, 250, timer::DEFAULT_TIMER_FREQUENCY] {
+        let before = timer::uptime_ms();
+        timer::init(frequency);
+        let start = timer::uptime_ms();
+        assert!(start >= before);
+        timer::busy_wait_us(SPAN_MS * 1000);
+        let elapsed = timer::uptime_ms() - start;
+        // a tick may be just about to arrive or to be missed at either end
+        let tick_ms = 1000 / frequency as u64;
+        assert!(
+            elapsed + 2 * tick_ms >= SPAN_MS && elapsed <= SPAN_MS + 2 * tick_ms + 10,
+            "{} ms of uptime passed in {} ms at {} Hz",
+            elapsed,
+            SPAN_MS,
+            frequency
+        );
+    }
+}
@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::task::{
+    thread,
+    user::{self, ExitStatus, UserError},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+const GENERAL_PROTECTION_FAULT: ExitStatus = ExitStatus::Killed("GENERAL PROTECTION FAULT");
+
+#[test_case]
+fn privileged_instructions_kill_the_task() {
+    // hlt
+    let task = user::spawn("hlt", &[0xF4]).unwrap();
+    assert_eq!(task.wait(), GENERAL_PROTECTION_FAULT);
+}
+
+#[test_case]
+fn programs_run_in_ring_3() {
+    let code = [
+        0x8C, 0xC8, // mov eax, cs
+        0x24, 0x03, // and al, 3
+        0x3C, 0x03, // cmp al, 3
+        0x75, 0x01, // jne +1
+        0xF4, // hlt
+        0x0F, 0x0B, // ud2
+    ];
+    let task = user::spawn("ring check", &code).unwrap();
+    assert_eq!(task.wait(), GENERAL_PROTECTION_FAULT);
+}
+
+#[test_case]
+fn user_stack_is_usable() {
+    let code = [
+        0x50, // push rax
+        0x58, // pop rax
+        0xF4, // hlt
+    ];
+    let task = user::spawn("stack", &code).unwrap();
+    assert_eq!(task.wait(), GENERAL_PROTECTION_FAULT);
+}
+
+#[test_case]
+fn kernel_memory_is_protected() {
+    let secret = Box::new(42u8);
+    let address = &*secret as *const u8 as u64;
+    let mut code = Vec::new();
+    code.extend_from_slice(&[0x48, 0xB8]); // movabs rax, address
+    code.extend_from_slice(&address.to_le_bytes());
+    code.extend_from_slice(&[0xC6, 0x00, 0x01]); // mov byte [rax], 1
+    let task = user::spawn("writer", &code).unwrap();
+    assert_eq!(task.wait(), ExitStatus::Killed("PAGE FAULT"));
+    assert_eq!(*secret, 42);
+}
+
+#[test_case]
+fn empty_programs_are_rejected() {
+    assert_eq!(user::spawn("empty", &[]).err(), Some(UserError::NoCode));
+}
+
+#[test_case]
+fn the_kernel_keeps_running() {
+    // dropped without waiting, the task cleans up after itself
+    drop(user::spawn("detached", &[0xF4]).unwrap());
+    thread::sleep(20);
+    for _ in 0..user::MAX_USER_TASKS + 4 {
+        let task = user::spawn("again", &[0xF4]).unwrap();
+        assert_eq!(task.wait(), GENERAL_PROTECTION_FAULT);
+    }
+    let handle = thread::spawn("after", || 7u8).unwrap();
+    assert_eq!(handle.join(), Some(7));
+}
@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use popcorn::{
+    low_level::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH},
+    println,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+/// Reads the character at the given position straight from VGA memory.
+fn screen_char(row: usize, col: usize) -> u8 {
+    let cell = (0xb8000 as *const u16).wrapping_add(row * BUFFER_WIDTH + col);
+    (unsafe { core::ptr::read_volatile(cell) } & 0xff) as u8
+}
+
+#[test_case]
+fn println_simple() {
+    println!("test_println_simple output");
+}
+
+#[test_case]
+fn println_many() {
+    for _ in 0..200 {
+        println!("test_println_many output");
+    }
+}
+
+#[test_case]
+fn println_output() {
+    let s = "Some test string that fits on a single line";
+    println!("{}", s);
+    // the writer always prints on the last row and scrolls up on a newline,
+    // the first column is left free for the cursor
+    for (i, c) in s.bytes().enumerate() {
+        assert_eq!(screen_char(BUFFER_HEIGHT - 2, i + 1), c);
+    }
+}
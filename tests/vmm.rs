@@ -0,0 +1,192 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(popcorn::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, ptr};
+use popcorn::low_level::{
+    memory,
+    vmm::{self, Backing, VmmError},
+};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageTableFlags, Translate},
+    PhysAddr, VirtAddr,
+};
+
+// nothing else is mapped or reserved up here
+const TEST_AREA: u64 = 0x_7777_0000_0000;
+const WRITABLE: PageTableFlags = PageTableFlags::WRITABLE;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    popcorn::init(boot_info);
+    test_main();
+    popcorn::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    popcorn::test_panic_handler(info)
+}
+
+fn is_mapped(address: VirtAddr) -> bool {
+    memory::with_mapper_and_allocator(|mapper, _| mapper.translate_addr(address).is_some()).unwrap()
+}
+
+#[test_case]
+fn pages_are_backed_on_first_access() {
+    let start = VirtAddr::new(TEST_AREA);
+    let area = vmm::reserve("test area", start, 16 * 4096, WRITABLE).expect("reserve failed");
+    assert_eq!(vmm::area_at(start + 4096u64 * 3), Some(area));
+    assert!(!is_mapped(start));
+
+    let faults = vmm::demand_faults();
+    let first = start.as_mut_ptr::<u64>();
+    let last = (area.end - 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr::read_volatile(first), 0);
+        ptr::write_volatile(first, 42);
+        ptr::write_volatile(last, 7);
+        assert_eq!(ptr::read_volatile(first), 42);
+        assert_eq!(ptr::read_volatile(last), 7);
+    }
+    assert_eq!(vmm::demand_faults(), faults + 2);
+    assert!(is_mapped(start));
+    assert!(!is_mapped(start + 4096u64));
+
+    assert!(vmm::release(start));
+    assert!(!is_mapped(start));
+    assert_eq!(vmm::area_at(start), None);
+}
+
+#[test_case]
+fn faults_are_handled_with_interrupts_on_and_off() {
+    let start = VirtAddr::new(TEST_AREA + 0x300_0000);
+    vmm::reserve("interrupts test area", start, 2 * 4096, WRITABLE).unwrap();
+    let faults = vmm::demand_faults();
+    // with interrupts on the handler waits for the locks, with them off it only tries
+    assert!(interrupts::are_enabled());
+    unsafe { ptr::write_volatile(start.as_mut_ptr::<u8>(), 1) };
+    interrupts::without_interrupts(|| unsafe {
+        ptr::write_volatile((start + 4096u64).as_mut_ptr::<u8>(), 2)
+    });
+    assert_eq!(vmm::demand_faults(), faults + 2);
+    assert!(vmm::release(start));
+}
+
+#[test_case]
+fn populate_backs_pages_up_front() {
+    let start = VirtAddr::new(TEST_AREA + 0x400_0000);
+    let area = vmm::reserve("populated area", start, 4 * 4096, WRITABLE).unwrap();
+    let faults = vmm::demand_faults();
+    assert!(vmm::populate(start + 4095u64, 2));
+    assert!(is_mapped(start) && is_mapped(start + 4096u64));
+    assert!(!is_mapped(start + 2 * 4096u64));
+    assert_eq!(vmm::demand_faults(), faults + 2);
+    assert!(!vmm::populate(start, area.size() + 1));
+    assert!(vmm::release(start));
+}
+
+#[test_case]
+fn read_only_areas_read_zeros() {
+    let start = VirtAddr::new(TEST_AREA + 0x100_0000);
+    vmm::reserve("read only test area", start, 4096, PageTableFlags::empty()).unwrap();
+    assert_eq!(unsafe { ptr::read_volatile(start.as_ptr::<u64>()) }, 0);
+    assert!(vmm::release(start));
+}
+
+#[test_case]
+fn invalid_reservations_are_rejected() {
+    let start = VirtAddr::new(TEST_AREA + 0x200_0000);
+    assert_eq!(
+        vmm::reserve("test area", start + 1u64, 4096, WRITABLE),
+        Err(VmmError::InvalidRange)
+    );
+    assert_eq!(
+        vmm::reserve("test area", start, 0, WRITABLE),
+        Err(VmmError::InvalidRange)
+    );
+    vmm::reserve("test area", start, 8 * 4096, WRITABLE).unwrap();
+    assert_eq!(
+        vmm::reserve("test area", start + 4096u64 * 7, 4096 * 2, WRITABLE),
+        Err(VmmError::Overlaps)
+    );
+    assert!(vmm::release(start));
+    assert!(!vmm::release(start));
+}
+
+#[test_case]
+fn vmalloc_hands_out_separate_zeroed_ranges() {
+    let first = vmm::vmalloc(3 * 4096).expect("vmalloc failed");
+    let second = vmm::vmalloc(100).expect("vmalloc failed");
+    let first_area = vmm::area_at(first).unwrap();
+    assert_eq!(first_area.size(), 3 * 4096);
+    assert!(first_area.end < second || vmm::area_at(second).unwrap().end < first);
+    unsafe {
+        let last = (first + 3 * 4096u64 - 8u64).as_mut_ptr::<u64>();
+        assert_eq!(ptr::read_volatile(last), 0);
+        ptr::write_volatile(last, 1);
+    }
+    let faults = vmm::demand_faults();
+    unsafe { ptr::write_volatile(second.as_mut_ptr::<u8>(), 1) };
+    assert_eq!(vmm::demand_faults(), faults);
+
+    assert!(vmm::vfree(first));
+    assert!(vmm::vfree(second));
+    assert!(!vmm::vfree(second));
+    assert!(!is_mapped(first));
+}
+
+#[test_case]
+fn vfree_only_frees_vmalloc_memory() {
+    // the name doesn't make it vmalloc memory
+    let area = vmm::allocate(
+        "vmalloc",
+        4096,
+        WRITABLE,
+        Backing::Anonymous { on_demand: true },
+    )
+    .expect("allocate failed");
+    assert!(!vmm::vfree(area.start));
+    assert_eq!(vmm::area_at(area.start), Some(area));
+    assert!(vmm::release(area.start));
+}
+
+#[test_case]
+fn mmio_maps_the_physical_address() {
+    // the VGA text buffer, also reachable through the physical memory mapping
+    let physical = PhysAddr::new(0xb8000 + 8);
+    let mapped = vmm::map_mmio(physical, 8).expect("map_mmio failed");
+    assert_eq!(mapped.as_u64() % 4096, 8);
+    let translated =
+        memory::with_mapper_and_allocator(|mapper, _| mapper.translate_addr(mapped)).unwrap();
+    assert_eq!(translated, Some(physical));
+    assert!(vmm::release(mapped.align_down(4096u64)));
+}
+
+#[test_case]
+fn the_heap_lives_in_its_own_area() {
+    let value = alloc::boxed::Box::new(5u64);
+    let area = vmm::area_at(VirtAddr::from_ptr(&*value)).expect("heap outside of any area");
+    assert_eq!(area.name, "kernel heap");
+}
+
+#[test_case]
+fn bootloader_mappings_are_kept() {
+    let vga_buffer = VirtAddr::new(0xb8000);
+    let area = vmm::area_at(vga_buffer).expect("vga buffer outside of any area");
+    assert_eq!(area.backing, Backing::Identity);
+    assert_eq!(
+        vmm::reserve("on top of the vga buffer", vga_buffer, 4096, WRITABLE),
+        Err(VmmError::Overlaps)
+    );
+    // only pages the vmm mapped itself are unmapped with their area
+    assert!(vmm::release(vga_buffer));
+    assert!(is_mapped(vga_buffer));
+}